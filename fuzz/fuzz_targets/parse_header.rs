@@ -0,0 +1,24 @@
+#![no_main]
+
+// mmproxy is a binary crate, so the parser is pulled in by path
+#[allow(dead_code)]
+#[path = "../../src/header/mod.rs"]
+mod header;
+
+use header::{tlv::Tlvs, ParseResult};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    match header::parse(data) {
+        ParseResult::Complete { len, header } => {
+            assert!(len <= data.len());
+            assert!(len <= header::MAX_HEADER_LEN);
+            let _ = Tlvs::parse(header.tlvs);
+
+            // the header is complete only once its last byte has arrived
+            assert_eq!(header::parse(&data[..len - 1]), ParseResult::Incomplete);
+        }
+        ParseResult::Incomplete => assert_ne!(header::has_signature(data), Some(false)),
+        ParseResult::Invalid(_) => {}
+    }
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+// mmproxy is a binary crate, so the parser is pulled in by path
+#[allow(dead_code)]
+#[path = "../../src/header/mod.rs"]
+mod header;
+
+use header::tlv::{self, Tlvs};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Tlvs::parse(data);
+
+    for kind in [tlv::PP2_TYPE_CRC32C, tlv::PP2_TYPE_SSL] {
+        if let Some(offset) = tlv::value_offset(data, kind) {
+            assert!(offset <= data.len());
+            assert_eq!(data[offset - 3], kind);
+        }
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+
+// mmproxy is a binary crate, so the parser is pulled in by path
+#[allow(dead_code)]
+#[path = "../../src/tls.rs"]
+mod tls;
+
+use libfuzzer_sys::fuzz_target;
+use tls::Peek;
+
+fuzz_target!(|data: &[u8]| {
+    if let Peek::Hello(_) = tls::peek(data) {
+        // more data never turns a complete ClientHello into something else
+        let mut more = data.to_vec();
+        more.push(0);
+        assert!(matches!(tls::peek(&more), Peek::Hello(_)));
+    }
+});
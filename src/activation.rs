@@ -0,0 +1,213 @@
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::{args::Args, util::Protocol};
+use socket2::{Socket, Type};
+use std::{
+    env, io,
+    mem::ManuallyDrop,
+    net::SocketAddr,
+    os::fd::{FromRawFd, RawFd},
+    process,
+};
+
+// the first of the sockets that systemd passes on, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+// a socket that was bound before mmproxy started
+#[derive(Debug)]
+struct Activated {
+    name: Option<String>,
+    socket: Socket,
+    kind: Type,
+    addr: Option<SocketAddr>,
+}
+
+// the sockets that systemd's socket activation, or the process before a hot
+// upgrade, passed on, until the listeners claim them
+#[derive(Debug, Default)]
+pub struct Sockets(Vec<Activated>);
+
+impl Sockets {
+    // takes the sockets from LISTEN_FDS, LISTEN_PID and LISTEN_FDNAMES, which
+    // are cleared so that they aren't passed on to children
+    pub fn from_env() -> Result<Self> {
+        let pid = env::var("LISTEN_PID").ok();
+        let count = env::var("LISTEN_FDS").ok();
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(var);
+        }
+
+        // the sockets may have been meant for a parent process
+        let (Some(pid), Some(count)) = (pid, count) else {
+            return Ok(Self::default());
+        };
+        if pid.parse() != Ok(process::id()) {
+            return Ok(Self::default());
+        }
+        let count: RawFd = count
+            .parse()
+            .map_err(|_| eyre!("invalid LISTEN_FDS: {count}"))?;
+
+        let fds: Vec<_> = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+        for &fd in &fds {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error())
+                    .wrap_err_with(|| format!("invalid activated socket {fd}"));
+            }
+        }
+        Self::from_fds(&fds, &names)
+    }
+
+    // `names` are separated by ':', like LISTEN_FDNAMES
+    pub fn from_fds(fds: &[RawFd], names: &str) -> Result<Self> {
+        let names: Vec<_> = names.split(':').collect();
+
+        let mut sockets = Vec::new();
+        for (i, &fd) in fds.iter().enumerate() {
+            // the fd is only owned once it's known to be a socket, so that an
+            // fd that wasn't passed on at all isn't closed by mistake
+            let socket = ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) });
+            let kind = socket
+                .r#type()
+                .wrap_err_with(|| format!("activated fd {fd} isn't a socket"))?;
+            let socket = ManuallyDrop::into_inner(socket);
+            let addr = socket.local_addr().ok().and_then(|addr| addr.as_socket());
+            let name = names
+                .get(i)
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string());
+
+            log::debug!(
+                "activated socket {fd}: {} {}",
+                name.as_deref().unwrap_or("-"),
+                addr.map_or("-".into(), |addr| addr.to_string())
+            );
+            sockets.push(Activated {
+                name,
+                socket,
+                kind,
+                addr,
+            });
+        }
+
+        Ok(Self(sockets))
+    }
+
+    // the sockets of a listener: the ones named --fd-name, or else the ones
+    // bound to its address, of the type its protocol needs; a named listener
+    // listens wherever its sockets are bound
+    pub fn claim(&mut self, args: &mut Args) -> Result<Vec<Socket>> {
+        let kind = match args.protocol {
+            Protocol::Tcp => Type::STREAM,
+            Protocol::Udp => Type::DGRAM,
+        };
+
+        let (claimed, rest): (Vec<_>, Vec<_>) = self.0.drain(..).partition(|activated| {
+            activated.kind == kind
+                && match args.fd_name {
+                    Some(ref name) => activated.name.as_ref() == Some(name),
+                    None => activated.addr == Some(args.listen_addr),
+                }
+        });
+        self.0 = rest;
+
+        if let Some(ref name) = args.fd_name {
+            let first = claimed
+                .first()
+                .ok_or_else(|| eyre!("no activated {} socket named {name}", args.protocol))?;
+            if let Some(addr) = first.addr {
+                args.listen_addr = addr;
+            }
+        }
+
+        Ok(claimed
+            .into_iter()
+            .map(|activated| activated.socket)
+            .collect())
+    }
+
+    // the sockets that no listener claimed are closed
+    pub fn close_unclaimed(self) {
+        for activated in self.0 {
+            log::warn!(
+                "activated socket {} isn't used by any listener",
+                match (activated.name, activated.addr) {
+                    (Some(name), _) => name,
+                    (None, Some(addr)) => addr.to_string(),
+                    (None, None) => "-".into(),
+                }
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::File,
+        net::{TcpListener, UdpSocket},
+        os::fd::{AsRawFd, IntoRawFd},
+    };
+
+    fn args(protocol: Protocol, fd_name: Option<&str>, listen_addr: SocketAddr) -> Args {
+        let mut args = Args::parse(Vec::<String>::new()).unwrap();
+        args.protocol = protocol;
+        args.fd_name = fd_name.map(str::to_owned);
+        args.listen_addr = listen_addr;
+        args
+    }
+
+    #[test]
+    fn claims_by_name_and_address() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (tcp_addr, udp_addr) = (tcp.local_addr().unwrap(), udp.local_addr().unwrap());
+        let fds = [tcp.into_raw_fd(), udp.into_raw_fd(), other.into_raw_fd()];
+        let mut sockets = Sockets::from_fds(&fds, "web::").unwrap();
+        assert_eq!(sockets.0.len(), 3);
+        assert_eq!(sockets.0[0].kind, Type::STREAM);
+        assert_eq!(sockets.0[1].kind, Type::DGRAM);
+        assert_eq!(sockets.0[1].name, None);
+
+        // a named listener moves to the address of its socket
+        let mut web = args(Protocol::Tcp, Some("web"), "0.0.0.0:80".parse().unwrap());
+        let claimed = sockets.claim(&mut web).unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].as_raw_fd(), fds[0]);
+        assert_eq!(web.listen_addr, tcp_addr);
+
+        // an unnamed one takes the sockets of its type on its address
+        let mut tcp = args(Protocol::Tcp, None, udp_addr);
+        assert!(sockets.claim(&mut tcp).unwrap().is_empty());
+        let mut udp = args(Protocol::Udp, None, udp_addr);
+        let claimed = sockets.claim(&mut udp).unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].as_raw_fd(), fds[1]);
+
+        assert_eq!(sockets.0.len(), 1);
+        let mut dns = args(Protocol::Udp, Some("dns"), udp_addr);
+        assert!(sockets.claim(&mut dns).is_err());
+        sockets.close_unclaimed();
+    }
+
+    #[test]
+    fn fewer_names_than_sockets() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let fds = [tcp.into_raw_fd(), other.into_raw_fd()];
+        let sockets = Sockets::from_fds(&fds, "web").unwrap();
+        assert_eq!(sockets.0[0].name.as_deref(), Some("web"));
+        assert_eq!(sockets.0[1].name, None);
+    }
+
+    #[test]
+    fn rejects_fds_that_arent_sockets() {
+        let file = File::open("/dev/null").unwrap();
+        assert!(Sockets::from_fds(&[file.as_raw_fd()], "").is_err());
+        // and leaves them open
+        assert!(file.metadata().is_ok());
+    }
+}
@@ -1,72 +1,468 @@
-use crate::util::{self, Protocol};
-use std::{net::SocketAddr, time::Duration};
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::{
+    config,
+    pool::{self, Policy, Pool},
+    route::{self, CrossFamily, Route, SendProxy, Target, TlvFilter, Unmatched},
+    tls::ClientHello,
+    util::{self, ChecksumMode, HeaderPolicy, HeaderVersions, LocalAction, Protocol, Upstream},
+};
+use arc_swap::ArcSwap;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+// the arguments of a running listener, which a reload swaps for new connections
+pub type SharedArgs = Arc<ArcSwap<Args>>;
+
+// the pools of every listener as they are now
+pub fn all_pools(listeners: &[SharedArgs]) -> Vec<Pool> {
+    listeners
+        .iter()
+        .flat_map(|args| args.load().pools().cloned().collect::<Vec<_>>())
+        .collect()
+}
 
 argwerk::define! {
     #[usage = "mmproxy [-h] [options]"]
     #[derive(Clone)]
     pub struct Args {
         pub help: bool = false,
-        pub ipv4_fwd: SocketAddr = "127.0.0.1:443".parse().unwrap(),
-        pub ipv6_fwd: SocketAddr = "[::1]:443".parse().unwrap(),
+        pub config: Option<String> = None,
+        // the files that the arguments were read from, see `reload`
+        pub files: Vec<String> = Vec::new(),
+        pub ipv4_fwd: Pool = pool::parse_pool("127.0.0.1:443").unwrap(),
+        pub ipv6_fwd: Pool = pool::parse_pool("[::1]:443").unwrap(),
+        pub balance: Policy = Policy::RoundRobin,
+        pub cross_family: CrossFamily = CrossFamily::Reject,
+        pub resolve_ttl: Duration = Duration::from_secs(30),
         pub allowed_subnets: Option<Vec<cidr::IpCidr>> = None,
+        pub routes: Vec<Route> = Vec::new(),
+        pub unmatched: Unmatched = Unmatched::Forward,
+        pub send_proxy: Option<SendProxy> = None,
+        pub send_tlvs: TlvFilter = TlvFilter::None,
+        pub connect_timeout: Option<Duration> = Some(Duration::from_secs(10)),
+        pub retries: u32 = 0,
+        pub retry_backoff: Option<Duration> = None,
         pub close_after: Duration = Duration::from_secs(60),
+        pub drain_timeout: Duration = Duration::from_secs(30),
         pub mark: u32 = 0,
         pub listen_addr: SocketAddr = "0.0.0.0:8443".parse().unwrap(),
         pub listeners: u32 = 1,
-        pub protocol: Protocol = Protocol::Tcp
+        pub backlog: u32 = 1024,
+        pub pin_listeners: bool = false,
+        pub fd_name: Option<String> = None,
+        pub protocols: Vec<Protocol> = vec![Protocol::Tcp],
+        // the one of `protocols` that a listener runs, see `listeners()`
+        pub protocol: Protocol = Protocol::Tcp,
+        pub crc32c: ChecksumMode = ChecksumMode::Reject,
+        pub header_policy: HeaderPolicy = HeaderPolicy::Required,
+        pub header_versions: HeaderVersions = HeaderVersions::Any,
+        pub header_timeout: Option<Duration> = Some(Duration::from_secs(10)),
+        pub hello_timeout: Duration = Duration::from_millis(3000),
+        pub defer_accept: u32 = 0,
+        pub metrics_addr: Option<SocketAddr> = None,
+        pub local_action: LocalAction = LocalAction::Proxy,
+        pub local_banner: Vec<u8> = b"OK\n".to_vec(),
+        pub health_upstream: Option<SocketAddr> = None,
+        pub health_interval: Option<Duration> = None,
+        pub health_timeout: Duration = Duration::from_secs(2),
+        pub health_rise: u32 = 2,
+        pub health_fall: u32 = 3,
+        pub health_send: Vec<u8> = Vec::new(),
+        pub health_expect: Vec<u8> = Vec::new()
     }
     /// Prints the help string.
     ["-h" | "--help"] => {
         println!("{}", Args::help());
         help = true;
     }
-    /// Address to which IPv4 traffic will be forwarded to. (default: "127.0.0.1:443")
-    ["-4" | "--ipv4", addr] => {
-        ipv4_fwd = addr.parse()?;
+    /// Path to a file of listeners, one per line: "<tcp|udp> <address> [option=value]..."; the other flags are their defaults.
+    ["--config", path] => {
+        files.push(path.clone());
+        config = Some(path);
+    }
+    /// Addresses or host:port names to which IPv4 traffic will be forwarded to, or unix:<path>, comma separated with an optional *<weight>, or none. (default: "127.0.0.1:443")
+    ["-4" | "--ipv4", addrs] => {
+        ipv4_fwd = match &addrs[..] {
+            "none" => Pool::new(Vec::new()),
+            _ => pool::parse_pool(&addrs)?,
+        };
+    }
+    /// Addresses or host:port names to which IPv6 traffic will be forwarded to, or unix:<path>, comma separated with an optional *<weight>, or none. (default: "[::1]:443")
+    ["-6" | "--ipv6", addrs] => {
+        ipv6_fwd = match &addrs[..] {
+            "none" => Pool::new(Vec::new()),
+            _ => pool::parse_pool(&addrs)?,
+        };
+    }
+    /// What to do with transparent connections that have no upstream of their family: reject, reemit (to the other family, with a PROXY v2 header). (default: reject)
+    ["--cross-family", policy] => {
+        cross_family = match &policy.to_lowercase()[..] {
+            "reject" => CrossFamily::Reject,
+            "reemit" => CrossFamily::Reemit,
+            _ => return Err(format!("invalid cross family value: {policy}").into()),
+        };
+    }
+    /// How connections are spread over the --ipv4/--ipv6 addresses: round-robin, least-conn, random-two, hash. (default: round-robin)
+    ["--balance", policy] => {
+        balance = policy.parse()?;
     }
-    /// Address to which IPv6 traffic will be forwarded to. (default: "[::1]:443")
-    ["-6" | "--ipv6", addr] => {
-        ipv6_fwd = addr.parse()?;
+    /// Number of seconds after which upstream names are resolved again. (default: 30)
+    ["--resolve-ttl", n] => {
+        resolve_ttl = match str::parse(&n)? {
+            0 => return Err("--resolve-ttl can't be 0".into()),
+            n => Duration::from_secs(n),
+        };
     }
     /// Path to a file that contains allowed subnets of the proxy servers.
     ["-a" | "--allowed-subnets", path] => {
         let ret = util::parse_allowed_subnets(&path)?;
+        files.push(path);
         allowed_subnets = if !ret.is_empty() { Some (ret) } else { None }
     }
+    /// Path to a file that contains routes on the PROXY header destination: "<cidr|*> <ports|*> <upstream>...".
+    ["-r" | "--routes", path] => {
+        routes = route::parse_routes(&path)?;
+        files.push(path);
+    }
+    /// What to do with connections that match no route: forward (to --ipv4/--ipv6), reject. (default: forward)
+    ["--unmatched", action] => {
+        unmatched = action.parse()?;
+    }
+    /// Re-emit a PROXY header of this version to the --ipv4/--ipv6 upstreams instead of using IP_TRANSPARENT: v1, v2. (default: disabled)
+    ["--send-proxy", version] => {
+        send_proxy = Some(SendProxy::new(&version, TlvFilter::None)?);
+    }
+    /// Client TLVs passed on with --send-proxy v2: none, all, or a list like "authority,alpn,0xE0". (default: none)
+    ["--send-tlvs", list] => {
+        send_tlvs = list.parse()?;
+    }
+    /// Number of seconds an upstream has to accept a connection, 0 leaves it to the kernel. (default: 10)
+    ["--connect-timeout", n] => {
+        connect_timeout = match str::parse(&n)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+    }
+    /// Number of other upstreams that are tried when connecting to one fails. (default: 0)
+    ["--retries", n] => {
+        retries = str::parse(&n)?;
+    }
+    /// Milliseconds to wait before the first retry, doubled for every one after it. (default: 0)
+    ["--retry-backoff", n] => {
+        retry_backoff = match str::parse(&n)? {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        };
+    }
     /// Number of seconds after which UDP socket will be cleaned up. (default: 60)
     ["-c" | "--close-after", n] => {
         close_after = Duration::from_secs(str::parse(&n)?);
     }
+    /// Number of seconds TCP connections are given to finish on SIGTERM, SIGINT or a hot upgrade before they're closed. (default: 30)
+    ["--drain-timeout", n] => {
+        drain_timeout = Duration::from_secs(str::parse(&n)?);
+    }
     /// Address the proxy listens on. (default: "0.0.0.0:8443")
     ["-l" | "--listen-addr", string] => {
         listen_addr = string.parse()?;
     }
-    /// Number of listener sockets that will be opened for the listen address. (Linux 3.9+) (default: 1)
+    /// Number of SO_REUSEPORT sockets opened for the listen address, each with its own accept or recv loop. (Linux 3.9+) (default: 1)
     ["--listeners", n] => {
-        listeners = str::parse(&n)?;
+        listeners = match str::parse(&n)? {
+            0 => return Err("--listeners can't be 0".into()),
+            n => n,
+        };
+    }
+    /// Listen on the systemd activated sockets of this name instead of binding the listen address; unnamed ones are matched by their address. (default: none)
+    ["--fd-name", name] => {
+        fd_name = Some(name);
+    }
+    /// Maximum number of TCP connections waiting to be accepted, per socket. (default: 1024)
+    ["--backlog", n] => {
+        backlog = str::parse(&n)?;
     }
-    /// Protocol that will be proxied: tcp, udp. (default: tcp)
+    /// Run every listener socket on a thread of its own, with a current-thread runtime pinned to a CPU.
+    ["--pin-listeners"] => {
+        pin_listeners = true;
+    }
+    /// Protocol that will be proxied: tcp, udp, both (tcp and udp on the same address). (default: tcp)
     ["-p" | "--protocol", p] => {
-        protocol = match &p.to_lowercase()[..] {
-            "tcp" => Protocol::Tcp,
-            "udp" => Protocol::Udp,
-            _ => return Err(format!("invalid protocol value: {p}").into()),
+        protocols = util::parse_protocols(&p)?;
+    }
+    /// What to do with PROXY v2 headers whose CRC32C checksum doesn't match: reject, log. (default: reject)
+    ["--crc32c", mode] => {
+        crc32c = match &mode.to_lowercase()[..] {
+            "reject" => ChecksumMode::Reject,
+            "log" => ChecksumMode::Log,
+            _ => return Err(format!("invalid crc32c value: {mode}").into()),
+        };
+    }
+    /// Whether connections must carry a PROXY header: required, optional, forbidden. (default: required)
+    ["--header-policy", mode] => {
+        header_policy = match &mode.to_lowercase()[..] {
+            "required" => HeaderPolicy::Required,
+            "optional" => HeaderPolicy::Optional,
+            "forbidden" => HeaderPolicy::Forbidden,
+            _ => return Err(format!("invalid header policy value: {mode}").into()),
+        };
+    }
+    /// PROXY protocol versions that are accepted: v1, v2, any. (default: any)
+    ["--header-versions", v] => {
+        header_versions = match &v.to_lowercase()[..] {
+            "v1" | "1" => HeaderVersions::V1,
+            "v2" | "2" => HeaderVersions::V2,
+            "any" => HeaderVersions::Any,
+            _ => return Err(format!("invalid header versions value: {v}").into()),
+        };
+    }
+    /// Number of seconds a TCP client has to send the PROXY header, 0 disables the deadline. (default: 10)
+    ["--header-timeout", n] => {
+        header_timeout = match str::parse(&n)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+    }
+    /// How long to wait for the TLS ClientHello when routes match on sni or alpn, in milliseconds; connections without one in time are routed without it. (default: 3000)
+    ["--hello-timeout", n] => {
+        hello_timeout = Duration::from_millis(str::parse(&n)?);
+    }
+    /// Number of seconds the kernel waits for data before handing over a TCP connection, 0 disables TCP_DEFER_ACCEPT. (default: 0)
+    ["--defer-accept", n] => {
+        defer_accept = match str::parse(&n)? {
+            n if n > i32::MAX as u32 => {
+                return Err(format!("--defer-accept can't be more than {}", i32::MAX).into())
+            }
+            n => n,
+        };
+    }
+    /// Address on which Prometheus metrics are served. (default: disabled)
+    ["--metrics-addr", addr] => {
+        metrics_addr = Some(addr.parse()?);
+    }
+    /// What to do with PROXY v2 LOCAL connections, e.g. health checks: proxy, close, banner, forward. (default: proxy)
+    ["--local-command", action] => {
+        local_action = match &action.to_lowercase()[..] {
+            "proxy" => LocalAction::Proxy,
+            "close" => LocalAction::Close,
+            "banner" => LocalAction::Banner,
+            "forward" => LocalAction::Forward,
+            _ => return Err(format!("invalid local command value: {action}").into()),
+        };
+    }
+    /// Reply sent to LOCAL connections in banner mode, supports \r, \n, \t and \\ escapes. (default: "OK\n")
+    ["--local-banner", text] => {
+        local_banner = util::unescape(&text);
+    }
+    /// Address to which LOCAL connections are forwarded to, from mmproxy's own address.
+    ["--health-upstream", addr] => {
+        health_upstream = Some(addr.parse()?);
+    }
+    /// Number of seconds between health checks of every upstream, over the listener protocol; 0 disables them. (default: 0)
+    ["--health-interval", n] => {
+        health_interval = match str::parse(&n)? {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
         };
     }
+    /// Number of seconds a health check may take. (default: 2)
+    ["--health-timeout", n] => {
+        health_timeout = Duration::from_secs(str::parse(&n)?);
+    }
+    /// Number of passed checks after which an unhealthy upstream is picked again. (default: 2)
+    ["--health-rise", n] => {
+        health_rise = str::parse(&n)?;
+    }
+    /// Number of failed checks after which an upstream is no longer picked. (default: 3)
+    ["--health-fall", n] => {
+        health_fall = str::parse(&n)?;
+    }
+    /// Payload sent by health checks, supports \r, \n, \t and \\ escapes. (default: none)
+    ["--health-send", text] => {
+        health_send = util::unescape(&text);
+    }
+    /// What the reply to a health check has to start with, supports \r, \n, \t and \\ escapes. (default: anything)
+    ["--health-expect", text] => {
+        health_expect = util::unescape(&text);
+    }
     /// The mark that will be set on outbound packets. (default: 0)
     ["-m" | "--mark", n] => {
         mark = str::parse::<u32>(&n)?;
     }
 }
 
-pub fn parse_args() -> Result<Args, argwerk::Error> {
+impl Args {
+    // picks the upstream for a connection from `src` to `dst`, as told by the
+    // PROXY header; the first matching route wins, and the upstreams that were
+    // already `tried` for the connection are avoided
+    pub fn upstream_for(
+        &self,
+        src: &SocketAddr,
+        dst: &SocketAddr,
+        hello: Option<&ClientHello>,
+        tried: &[Upstream],
+    ) -> Result<Target<'_>> {
+        match self.routes.iter().find(|route| route.matches(dst, hello)) {
+            Some(route) => route
+                .upstream_for(src, tried, self.cross_family)
+                .ok_or_else(|| eyre!("the route for {dst} has no upstream available for {src}")),
+            None if self.unmatched == Unmatched::Reject => Err(eyre!("no route for {dst}")),
+            None => {
+                let (pool, other) = match src {
+                    SocketAddr::V4(_) => (&self.ipv4_fwd, &self.ipv6_fwd),
+                    SocketAddr::V6(_) => (&self.ipv6_fwd, &self.ipv4_fwd),
+                };
+                let send_proxy = self.send_proxy.as_ref();
+
+                match route::pick(pool, src, tried, send_proxy, CrossFamily::Reject) {
+                    Some(target) => Ok(target),
+                    None if self.cross_family == CrossFamily::Reemit => {
+                        route::pick(other, src, tried, send_proxy, self.cross_family)
+                            .ok_or_else(|| eyre!("no upstream available for {src}"))
+                    }
+                    None => Err(eyre!("no upstream available for {src}")),
+                }
+            }
+        }
+    }
+
+    // whether a connection to `dst` has to be peeked at for a TLS ClientHello,
+    // which is only needed until a route that matches without it comes first
+    pub fn peeks_hello(&self, dst: &SocketAddr) -> bool {
+        self.routes
+            .iter()
+            .find(|route| route.matches_addr(dst))
+            .is_some_and(Route::needs_hello)
+    }
+
+    // the settings that depend on the protocol of the listener
+    fn check(&self) -> Result<()> {
+        if self.protocol == Protocol::Udp && self.header_versions == HeaderVersions::V1 {
+            return Err(eyre!(
+                "proxy protocol version 1 doesn't support UDP connections"
+            ));
+        }
+        let sends_v1 = self
+            .routes
+            .iter()
+            .filter_map(|route| route.send_proxy.as_ref())
+            .chain(self.send_proxy.as_ref())
+            .any(|send_proxy| send_proxy.version == 1);
+        if self.protocol == Protocol::Udp && sends_v1 {
+            return Err(eyre!(
+                "proxy protocol version 1 can't be sent for UDP connections"
+            ));
+        }
+        if self.protocol == Protocol::Udp && self.routes.iter().any(Route::needs_hello) {
+            return Err(eyre!("sni and alpn routes require the tcp protocol"));
+        }
+
+        Ok(())
+    }
+
+    // the listeners to run: the ones of --config, or else the one of the
+    // command line; an address with several protocols gets a listener for
+    // each, which share everything else
+    pub fn listeners(self) -> Result<Vec<Args>> {
+        let configured = match self.config {
+            Some(ref path) => {
+                let configured = config::parse_listeners(path, &self)
+                    .wrap_err_with(|| format!("failed to read the config from {path}"))?;
+                if configured.is_empty() {
+                    return Err(eyre!("{path} doesn't define any listener"));
+                }
+                configured
+            }
+            None => vec![self],
+        };
+
+        let mut listeners = Vec::new();
+        for args in configured {
+            for &protocol in &args.protocols {
+                let mut listener = args.clone();
+                listener.protocol = protocol;
+                listener.check().wrap_err_with(|| {
+                    format!("invalid {protocol} listener {}", listener.listen_addr)
+                })?;
+                listeners.push(listener);
+            }
+        }
+
+        Ok(listeners)
+    }
+
+    // every upstream pool, the default ones first
+    pub fn pools(&self) -> impl Iterator<Item = &Pool> {
+        [&self.ipv4_fwd, &self.ipv6_fwd]
+            .into_iter()
+            .chain(self.routes.iter().map(|route| &route.upstreams))
+    }
+
+    pub fn pools_mut(&mut self) -> impl Iterator<Item = &mut Pool> {
+        [&mut self.ipv4_fwd, &mut self.ipv6_fwd]
+            .into_iter()
+            .chain(self.routes.iter_mut().map(|route| &mut route.upstreams))
+    }
+}
+
+pub fn parse_args() -> Result<Args> {
     match Args::args() {
-        Ok(args) => {
+        Ok(mut args) => {
             if args.help {
                 std::process::exit(1);
             }
+            if args.local_action == LocalAction::Forward && args.health_upstream.is_none() {
+                return Err(eyre!("--local-command forward requires --health-upstream"));
+            }
+            args.ipv4_fwd.policy = args.balance;
+            args.ipv6_fwd.policy = args.balance;
+            if args.send_tlvs != TlvFilter::None {
+                match args.send_proxy {
+                    Some(ref mut send_proxy) if send_proxy.version == 2 => {
+                        send_proxy.tlvs = args.send_tlvs.clone();
+                    }
+                    _ => return Err(eyre!("--send-tlvs requires --send-proxy v2")),
+                }
+            }
             Ok(args)
         }
-        Err(err) => Err(err),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(routes: &[&str]) -> Args {
+        let mut args = Args::parse(Vec::<String>::new()).unwrap();
+        args.routes = routes.iter().map(|line| line.parse().unwrap()).collect();
+        args
+    }
+
+    #[test]
+    fn peeks_hello_for_matching_routes() {
+        let args = args(&[
+            "10.0.0.0/8  443  127.0.0.1:8443  sni=example.com",
+            "10.0.0.0/8  443  127.0.0.1:9443",
+            "10.0.0.1    25   127.0.0.1:2525",
+            "*           *    127.0.0.1:8000  alpn=h2",
+        ]);
+        let peeks = |dst: &str| args.peeks_hello(&dst.parse().unwrap());
+
+        assert!(peeks("10.0.0.1:443"));
+        assert!(!peeks("10.0.0.1:25"));
+        assert!(peeks("10.0.0.1:80"));
+        assert!(peeks("192.0.2.1:443"));
+        assert!(!self::args(&[]).peeks_hello(&"10.0.0.1:443".parse().unwrap()));
+        assert!(!self::args(&["* 22 127.0.0.1:22"]).peeks_hello(&"10.0.0.1:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn defer_accept_fits_the_socket_option() {
+        let parse = |n: &str| Args::parse(["--defer-accept", n]).map(|args| args.defer_accept);
+
+        assert_eq!(parse("30").unwrap(), 30);
+        assert_eq!(parse("2147483647").unwrap(), i32::MAX as u32);
+        assert!(parse("2147483648").is_err());
     }
 }
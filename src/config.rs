@@ -0,0 +1,294 @@
+use crate::{args::Args, pool, route, util};
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    time::Duration,
+};
+
+// one listener per line, with options that override the command line, e.g.
+//
+//   # protocol   address          options
+//   tcp          0.0.0.0:443      routes=/etc/mmproxy/https.routes mark=123
+//   tcp          0.0.0.0:25       ipv4=127.0.0.1:2525 header-timeout=30
+//   tcp          0.0.0.0:80       fd=http
+//   both         [::]:53          allowed-subnets=/etc/mmproxy/lb.subnets close-after=10
+fn parse_listener(line: &str, defaults: &Args) -> Result<Args, String> {
+    let mut fields = line.split_whitespace();
+    let mut args = defaults.clone();
+
+    args.protocols = match fields.next() {
+        Some(protocols) => util::parse_protocols(protocols)?,
+        None => return Err("missing protocol".into()),
+    };
+    args.listen_addr = match fields.next() {
+        Some(addr) => addr
+            .parse()
+            .map_err(|_| format!("invalid address: {addr}"))?,
+        None => return Err("missing address".into()),
+    };
+
+    for field in fields {
+        let Some((option, value)) = field.split_once('=') else {
+            return Err(format!("invalid option: {field}"));
+        };
+        let invalid = |_| format!("invalid {option} value: {value}");
+
+        match option {
+            "ipv4" | "ipv6" => {
+                let mut pool = match value {
+                    "none" => pool::Pool::new(Vec::new()),
+                    _ => pool::parse_pool(value)?,
+                };
+                pool.policy = args.balance;
+                match option {
+                    "ipv4" => args.ipv4_fwd = pool,
+                    _ => args.ipv6_fwd = pool,
+                }
+            }
+            "allowed-subnets" => {
+                let subnets =
+                    util::parse_allowed_subnets(value).map_err(|why| format!("{value}: {why}"))?;
+                args.allowed_subnets = (!subnets.is_empty()).then_some(subnets);
+                args.files.push(value.to_owned());
+            }
+            "routes" => {
+                args.routes = route::parse_routes(value).map_err(|why| why.to_string())?;
+                args.files.push(value.to_owned());
+            }
+            "unmatched" => args.unmatched = value.parse()?,
+            "fd" => args.fd_name = Some(value.to_owned()),
+            "mark" => args.mark = value.parse().map_err(invalid)?,
+            "close-after" => {
+                args.close_after = Duration::from_secs(value.parse().map_err(invalid)?)
+            }
+            "header-timeout" => {
+                args.header_timeout = match value.parse().map_err(invalid)? {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                }
+            }
+            "hello-timeout" => {
+                args.hello_timeout = Duration::from_millis(value.parse().map_err(invalid)?)
+            }
+            "connect-timeout" => {
+                args.connect_timeout = match value.parse().map_err(invalid)? {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                }
+            }
+            _ => return Err(format!("unknown option: {option}")),
+        }
+    }
+
+    Ok(args)
+}
+
+// blank lines and lines starting with '#' are skipped
+pub fn parse_listeners(path: &str, defaults: &Args) -> io::Result<Vec<Args>> {
+    let mut data = Vec::new();
+    let mut file = File::open(path)?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_listener(line, defaults) {
+            Ok(args) => data.push(args),
+            Err(why) => {
+                return Err(io::Error::other(format!("{path}:{}: {why}", n + 1)));
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pool::Policy,
+        route::Unmatched,
+        util::{Protocol, Upstream},
+    };
+    use std::{env, fs};
+
+    fn defaults() -> Args {
+        Args::parse(["--balance", "least-conn"]).unwrap()
+    }
+
+    fn parse(line: &str) -> Result<Args, String> {
+        parse_listener(line, &defaults())
+    }
+
+    // a file of this test process, so that the tests can run side by side
+    fn file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("mmproxy-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn upstreams(pool: &pool::Pool) -> Vec<Upstream> {
+        pool.backends()
+            .iter()
+            .map(|backend| backend.upstream.clone())
+            .collect()
+    }
+
+    #[test]
+    fn protocol_and_address() {
+        let args = parse("udp 127.0.0.1:53").unwrap();
+        assert_eq!(args.protocols, [Protocol::Udp]);
+        assert_eq!(args.listen_addr, "127.0.0.1:53".parse().unwrap());
+
+        let args = parse("both [::]:53").unwrap();
+        assert_eq!(args.protocols, [Protocol::Tcp, Protocol::Udp]);
+        assert_eq!(args.listen_addr, "[::]:53".parse().unwrap());
+
+        // everything else is left to the defaults
+        let defaults = defaults();
+        assert_eq!(args.mark, defaults.mark);
+        assert_eq!(args.close_after, defaults.close_after);
+        assert_eq!(upstreams(&args.ipv4_fwd), upstreams(&defaults.ipv4_fwd));
+    }
+
+    #[test]
+    fn missing_or_invalid_address() {
+        assert_eq!(parse("").unwrap_err(), "missing protocol");
+        assert_eq!(parse("tcp").unwrap_err(), "missing address");
+        assert_eq!(
+            parse("tcp localhost").unwrap_err(),
+            "invalid address: localhost"
+        );
+        assert!(parse("sctp 0.0.0.0:443").is_err());
+    }
+
+    #[test]
+    fn upstream_options() {
+        let args =
+            parse("tcp 0.0.0.0:443 ipv4=127.0.0.1:8443,unix:/run/app.sock ipv6=none").unwrap();
+        assert_eq!(
+            upstreams(&args.ipv4_fwd),
+            [
+                Upstream::Inet("127.0.0.1:8443".parse().unwrap()),
+                Upstream::Unix("/run/app.sock".into()),
+            ]
+        );
+        assert!(upstreams(&args.ipv6_fwd).is_empty());
+        // the pools take the --balance policy
+        assert_eq!(args.ipv4_fwd.policy, Policy::LeastConn);
+        assert_eq!(args.ipv6_fwd.policy, Policy::LeastConn);
+
+        let args = parse("tcp 0.0.0.0:443 ipv6=[::1]:8443 unmatched=reject").unwrap();
+        assert_eq!(
+            upstreams(&args.ipv6_fwd),
+            [Upstream::Inet("[::1]:8443".parse().unwrap())]
+        );
+        assert_eq!(args.unmatched, Unmatched::Reject);
+    }
+
+    #[test]
+    fn file_options() {
+        let subnets = file("subnets", "10.0.0.0/8\n192.0.2.0/24\n");
+        let routes = file("routes", "10.0.0.0/8 443 127.0.0.1:8443\n");
+        let args = parse(&format!(
+            "tcp 0.0.0.0:443 allowed-subnets={subnets} routes={routes}"
+        ))
+        .unwrap();
+
+        assert_eq!(
+            args.allowed_subnets.unwrap(),
+            [
+                "10.0.0.0/8".parse().unwrap(),
+                "192.0.2.0/24".parse().unwrap()
+            ]
+        );
+        assert_eq!(args.routes.len(), 1);
+        // so that a reload reads them again
+        assert_eq!(args.files, [subnets.clone(), routes.clone()]);
+
+        // a file that doesn't parse names itself
+        let why = parse("tcp 0.0.0.0:443 allowed-subnets=/nonexistent").unwrap_err();
+        assert!(why.starts_with("/nonexistent: "), "{why}");
+        fs::remove_file(subnets).unwrap();
+        fs::remove_file(routes).unwrap();
+    }
+
+    #[test]
+    fn socket_and_timeout_options() {
+        let args = parse(
+            "tcp 0.0.0.0:443 fd=https mark=123 close-after=10 header-timeout=30 \
+             hello-timeout=250 connect-timeout=5",
+        )
+        .unwrap();
+        assert_eq!(args.fd_name.as_deref(), Some("https"));
+        assert_eq!(args.mark, 123);
+        assert_eq!(args.close_after, Duration::from_secs(10));
+        assert_eq!(args.header_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(args.hello_timeout, Duration::from_millis(250));
+        assert_eq!(args.connect_timeout, Some(Duration::from_secs(5)));
+
+        // 0 disables the deadlines that can be disabled
+        let args = parse("tcp 0.0.0.0:443 header-timeout=0 connect-timeout=0").unwrap();
+        assert_eq!(args.header_timeout, None);
+        assert_eq!(args.connect_timeout, None);
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert_eq!(
+            parse("tcp 0.0.0.0:443 mark=x").unwrap_err(),
+            "invalid mark value: x"
+        );
+        assert_eq!(
+            parse("tcp 0.0.0.0:443 close-after=-1").unwrap_err(),
+            "invalid close-after value: -1"
+        );
+        assert_eq!(
+            parse("tcp 0.0.0.0:443 retries=3").unwrap_err(),
+            "unknown option: retries"
+        );
+        assert_eq!(
+            parse("tcp 0.0.0.0:443 mark").unwrap_err(),
+            "invalid option: mark"
+        );
+    }
+
+    #[test]
+    fn listeners_file() {
+        let path = file(
+            "listeners",
+            "# protocol   address\n\
+             \n\
+             tcp 0.0.0.0:443 mark=1\n   \n\
+             \t# indented comment\n\
+             udp 0.0.0.0:53 mark=2\n",
+        );
+        let listeners = parse_listeners(&path, &defaults()).unwrap();
+        let marks: Vec<_> = listeners.iter().map(|args| args.mark).collect();
+        assert_eq!(marks, [1, 2]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn listeners_file_errors_carry_the_line() {
+        let path = file(
+            "broken",
+            "# protocol   address\n\
+             tcp 0.0.0.0:443\n\
+             \n\
+             tcp 0.0.0.0:80 foo=bar\n",
+        );
+        let why = parse_listeners(&path, &defaults()).unwrap_err();
+        assert_eq!(why.to_string(), format!("{path}:4: unknown option: foo"));
+        fs::remove_file(&path).unwrap();
+
+        assert!(parse_listeners(&path, &defaults()).is_err());
+    }
+}
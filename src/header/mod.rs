@@ -0,0 +1,692 @@
+// A zero-copy parser for PROXY protocol v1 and v2 headers.
+//
+// https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+
+pub mod tlv;
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::FromStr,
+};
+
+pub const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest possible v1 header, including the trailing CRLF
+pub const V1_MAX_LEN: usize = 107;
+pub const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+// signature, version/command, family/protocol and the 2-byte length
+pub const V2_PREAMBLE_LEN: usize = 16;
+// the longest possible header that can be sent to us
+pub const MAX_HEADER_LEN: usize = V2_PREAMBLE_LEN + u16::MAX as usize;
+// the size of `sun_path` in the v2 AF_UNIX address block
+pub const UNIX_PATH_LEN: usize = 108;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    // the connection was established by the proxy itself, e.g. a health check
+    Local,
+    Proxy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Unspec,
+    Stream,
+    Dgram,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addresses<'a> {
+    // v1 UNKNOWN, v2 AF_UNSPEC or any v2 LOCAL header
+    Unspec,
+    Inet { src: SocketAddr, dst: SocketAddr },
+    // the raw NUL-padded `sun_path` of both ends
+    Unix { src: &'a [u8], dst: &'a [u8] },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header<'a> {
+    pub version: u8,
+    pub command: Command,
+    pub transport: Transport,
+    pub addresses: Addresses<'a>,
+    // the raw TLV block at the end of a v2 header, empty for v1
+    pub tlvs: &'a [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseResult<'a> {
+    // the buffer is a valid prefix of a header, but more bytes are needed
+    Incomplete,
+    Invalid(&'static str),
+    // `len` is the length of the header, the payload starts right after it
+    Complete { len: usize, header: Header<'a> },
+}
+
+use ParseResult::{Complete, Incomplete, Invalid};
+
+// tells whether the buffer starts with a v1 or v2 signature, `None` if there
+// are too few bytes to tell
+pub fn has_signature(buffer: &[u8]) -> Option<bool> {
+    let mut undecided = false;
+
+    for signature in [V1_PREFIX, V2_SIGNATURE] {
+        if buffer.starts_with(signature) {
+            return Some(true);
+        }
+        undecided |= signature.starts_with(buffer);
+    }
+
+    if undecided {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+pub fn parse(buffer: &[u8]) -> ParseResult<'_> {
+    match has_signature(buffer) {
+        Some(true) if buffer.starts_with(V1_PREFIX) => parse_v1(buffer),
+        Some(true) => parse_v2(buffer),
+        Some(false) => Invalid("the given data is not a PROXY header"),
+        None => Incomplete,
+    }
+}
+
+fn parse_v1(buffer: &[u8]) -> ParseResult<'_> {
+    let searched = &buffer[..buffer.len().min(V1_MAX_LEN)];
+    let end = match searched.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buffer.len() >= V1_MAX_LEN => return Invalid("v1 header is longer than 107 bytes"),
+        None => return Incomplete,
+    };
+
+    let line = match std::str::from_utf8(&buffer[V1_PREFIX.len()..end]) {
+        Ok(line) => line,
+        Err(_) => return Invalid("v1 header is not valid ASCII"),
+    };
+    let mut fields = line.split(' ');
+
+    let addresses = match fields.next() {
+        // the receiver must ignore everything after UNKNOWN
+        Some("UNKNOWN") => Addresses::Unspec,
+        Some("TCP4") => match parse_v1_addresses::<Ipv4Addr>(&mut fields) {
+            Some((src, dst, sport, dport)) => Addresses::Inet {
+                src: SocketAddrV4::new(src, sport).into(),
+                dst: SocketAddrV4::new(dst, dport).into(),
+            },
+            None => return Invalid("invalid v1 TCP4 addresses"),
+        },
+        Some("TCP6") => match parse_v1_addresses::<Ipv6Addr>(&mut fields) {
+            Some((src, dst, sport, dport)) => Addresses::Inet {
+                src: SocketAddrV6::new(src, sport, 0, 0).into(),
+                dst: SocketAddrV6::new(dst, dport, 0, 0).into(),
+            },
+            None => return Invalid("invalid v1 TCP6 addresses"),
+        },
+        _ => return Invalid("unknown v1 protocol"),
+    };
+
+    Complete {
+        len: end + 2,
+        header: Header {
+            version: 1,
+            command: Command::Proxy,
+            transport: Transport::Stream,
+            addresses,
+            tlvs: &[],
+        },
+    }
+}
+
+// "<src> <dst> <sport> <dport>" with nothing after it
+fn parse_v1_addresses<'a, T: FromStr>(
+    fields: &mut impl Iterator<Item = &'a str>,
+) -> Option<(T, T, u16, u16)> {
+    let src = fields.next()?.parse().ok()?;
+    let dst = fields.next()?.parse().ok()?;
+    let sport = parse_v1_port(fields.next()?)?;
+    let dport = parse_v1_port(fields.next()?)?;
+
+    fields.next().is_none().then_some((src, dst, sport, dport))
+}
+
+// plain decimal without a sign or leading zeroes
+fn parse_v1_port(field: &str) -> Option<u16> {
+    let digits = !field.is_empty() && field.bytes().all(|b| b.is_ascii_digit());
+    if !digits || (field.len() > 1 && field.starts_with('0')) {
+        return None;
+    }
+
+    field.parse().ok()
+}
+
+fn parse_v2(buffer: &[u8]) -> ParseResult<'_> {
+    if buffer.len() < V2_PREAMBLE_LEN {
+        return Incomplete;
+    }
+
+    if buffer[12] >> 4 != 2 {
+        return Invalid("unsupported v2 header version");
+    }
+    let command = match buffer[12] & 0x0f {
+        0x0 => Command::Local,
+        0x1 => Command::Proxy,
+        _ => return Invalid("unknown v2 command"),
+    };
+    let transport = match buffer[13] & 0x0f {
+        0x0 => Transport::Unspec,
+        0x1 => Transport::Stream,
+        0x2 => Transport::Dgram,
+        _ => return Invalid("unknown v2 transport protocol"),
+    };
+
+    let len = V2_PREAMBLE_LEN + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+    if buffer.len() < len {
+        return Incomplete;
+    }
+    let body = &buffer[V2_PREAMBLE_LEN..len];
+
+    let addresses_len = match buffer[13] >> 4 {
+        0x0 => 0,
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 2 * UNIX_PATH_LEN,
+        _ => return Invalid("unknown v2 address family"),
+    };
+    if body.len() < addresses_len {
+        return Invalid("v2 address block is truncated");
+    }
+    let (addresses, tlvs) = body.split_at(addresses_len);
+
+    if tlv::iter(tlvs).any(|tlv| tlv.is_err()) {
+        return Invalid("malformed v2 TLVs");
+    }
+
+    let addresses = match (command, addresses_len) {
+        // the receiver must ignore the addresses of LOCAL headers
+        (Command::Local, _) | (_, 0) => Addresses::Unspec,
+        (_, 12) => {
+            let ip = |at: usize| Ipv4Addr::from(array::<4>(&addresses[at..]));
+            let port = |at: usize| u16::from_be_bytes(array(&addresses[at..]));
+            Addresses::Inet {
+                src: SocketAddrV4::new(ip(0), port(8)).into(),
+                dst: SocketAddrV4::new(ip(4), port(10)).into(),
+            }
+        }
+        (_, 36) => {
+            let ip = |at: usize| Ipv6Addr::from(array::<16>(&addresses[at..]));
+            let port = |at: usize| u16::from_be_bytes(array(&addresses[at..]));
+            Addresses::Inet {
+                src: SocketAddrV6::new(ip(0), port(32), 0, 0).into(),
+                dst: SocketAddrV6::new(ip(16), port(34), 0, 0).into(),
+            }
+        }
+        _ => {
+            let (src, dst) = addresses.split_at(UNIX_PATH_LEN);
+            Addresses::Unix { src, dst }
+        }
+    };
+
+    Complete {
+        len,
+        header: Header {
+            version: 2,
+            command,
+            transport,
+            addresses,
+            tlvs,
+        },
+    }
+}
+
+// the callers have already checked the length of the address block
+fn array<const N: usize>(buffer: &[u8]) -> [u8; N] {
+    let mut array = [0u8; N];
+    array.copy_from_slice(&buffer[..N]);
+    array
+}
+
+// encodes a v1 header, addresses that v1 can't carry are sent as UNKNOWN
+pub fn encode_v1(addresses: &Addresses<'_>) -> Vec<u8> {
+    let line = match *addresses {
+        Addresses::Inet {
+            src: SocketAddr::V4(src),
+            dst: SocketAddr::V4(dst),
+        } => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            src.ip(),
+            dst.ip(),
+            src.port(),
+            dst.port()
+        ),
+        Addresses::Inet { src, dst } => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            to_ipv6(src.ip()),
+            to_ipv6(dst.ip()),
+            src.port(),
+            dst.port()
+        ),
+        _ => "PROXY UNKNOWN\r\n".to_owned(),
+    };
+
+    line.into_bytes()
+}
+
+// the longest TLV block that fits in a v2 header along with the addresses
+pub fn max_v2_tlvs_len(addresses: &Addresses<'_>) -> usize {
+    u16::MAX as usize - v2_family(addresses).1
+}
+
+// the v2 address family and the length of its address block
+fn v2_family(addresses: &Addresses<'_>) -> (u8, usize) {
+    match addresses {
+        Addresses::Unspec => (0x0, 0),
+        // a mix of both is sent as IPv6, like in v1
+        Addresses::Inet { src, dst } if src.is_ipv4() && dst.is_ipv4() => (0x1, 12),
+        Addresses::Inet { .. } => (0x2, 36),
+        Addresses::Unix { .. } => (0x3, 2 * UNIX_PATH_LEN),
+    }
+}
+
+// encodes a v2 header, the TLV block is appended as it is, or left out if it
+// doesn't fit
+pub fn encode_v2(
+    command: Command,
+    transport: Transport,
+    addresses: &Addresses<'_>,
+    mut tlvs: &[u8],
+) -> Vec<u8> {
+    let (family, addresses_len) = v2_family(addresses);
+    if tlvs.len() > max_v2_tlvs_len(addresses) {
+        log::warn!(
+            "dropping {} bytes of TLVs that don't fit in the v2 header",
+            tlvs.len()
+        );
+        tlvs = &[];
+    }
+    let command = match command {
+        Command::Local => 0x0,
+        Command::Proxy => 0x1,
+    };
+    let transport = match transport {
+        Transport::Unspec => 0x0,
+        Transport::Stream => 0x1,
+        Transport::Dgram => 0x2,
+    };
+
+    let len = addresses_len + tlvs.len();
+    let mut out = Vec::with_capacity(V2_PREAMBLE_LEN + len);
+    out.extend_from_slice(V2_SIGNATURE);
+    out.push(0x20 | command);
+    out.push(family << 4 | transport);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+
+    match *addresses {
+        Addresses::Unspec => {}
+        Addresses::Inet { src, dst } => {
+            match (src, dst) {
+                (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                    out.extend_from_slice(&src.ip().octets());
+                    out.extend_from_slice(&dst.ip().octets());
+                }
+                _ => {
+                    out.extend_from_slice(&to_ipv6(src.ip()).octets());
+                    out.extend_from_slice(&to_ipv6(dst.ip()).octets());
+                }
+            }
+            out.extend_from_slice(&src.port().to_be_bytes());
+            out.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        Addresses::Unix { src, dst } => {
+            for path in [src, dst] {
+                let path = &path[..path.len().min(UNIX_PATH_LEN)];
+                out.extend_from_slice(path);
+                out.resize(out.len() + UNIX_PATH_LEN - path.len(), 0);
+            }
+        }
+    }
+    out.extend_from_slice(tlvs);
+
+    out
+}
+
+// both ends of a header have to be in the same family
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(buffer: &[u8]) -> (usize, Header<'_>) {
+        match parse(buffer) {
+            Complete { len, header } => (len, header),
+            other => panic!("{other:?}"),
+        }
+    }
+
+    fn inet(src: &str, dst: &str) -> Addresses<'static> {
+        Addresses::Inet {
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+        }
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = V2_SIGNATURE.to_vec();
+        out.push(command);
+        out.push(family);
+        out.extend((body.len() as u16).to_be_bytes());
+        out.extend(body);
+        out
+    }
+
+    #[test]
+    fn signature() {
+        assert_eq!(has_signature(b""), None);
+        assert_eq!(has_signature(b"PRO"), None);
+        assert_eq!(has_signature(b"\r\n\r\n"), None);
+        assert_eq!(has_signature(b"PROXY "), Some(true));
+        assert_eq!(has_signature(V2_SIGNATURE), Some(true));
+        assert_eq!(has_signature(b"GET / HTTP/1.1\r\n"), Some(false));
+        assert_eq!(has_signature(b"\r\n\r\n\0\r\nQUIX"), Some(false));
+        assert_eq!(parse(b"PROX"), Incomplete);
+        assert!(matches!(parse(b"GET /"), Invalid(_)));
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let buffer = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /";
+        let (len, header) = complete(buffer);
+        assert_eq!(len, buffer.len() - 5);
+        assert_eq!(header.version, 1);
+        assert_eq!(header.command, Command::Proxy);
+        assert_eq!(header.transport, Transport::Stream);
+        assert_eq!(
+            header.addresses,
+            inet("192.168.0.1:56324", "192.168.0.11:443")
+        );
+        assert!(header.tlvs.is_empty());
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let (_, header) = complete(b"PROXY TCP6 2001:db8::1 ::1 65535 0\r\n");
+        assert_eq!(header.addresses, inet("[2001:db8::1]:65535", "[::1]:0"));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        for buffer in [
+            &b"PROXY UNKNOWN\r\n"[..],
+            b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n",
+        ] {
+            let (len, header) = complete(buffer);
+            assert_eq!(len, buffer.len());
+            assert_eq!(header.addresses, Addresses::Unspec);
+        }
+    }
+
+    #[test]
+    fn v1_incomplete() {
+        assert_eq!(parse(b"PROXY TCP4 192.168.0.1"), Incomplete);
+        assert_eq!(parse(b"PROXY TCP4 1.1.1.1 2.2.2.2 1 2\r"), Incomplete);
+    }
+
+    #[test]
+    fn v1_length_limit() {
+        // the spec's worst case, UNKNOWN followed by the longest TCP6 addresses
+        let ip = "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff";
+        let longest = format!("PROXY UNKNOWN {ip} {ip} 65535 65535\r\n");
+        assert_eq!(longest.len(), V1_MAX_LEN);
+        assert_eq!(complete(longest.as_bytes()).0, V1_MAX_LEN);
+
+        let longer = format!("PROXY UNKNOWN {}\r\n", "x".repeat(V1_MAX_LEN));
+        assert_eq!(
+            parse(longer.as_bytes()),
+            Invalid("v1 header is longer than 107 bytes")
+        );
+        assert_eq!(
+            parse(&longer.as_bytes()[..V1_MAX_LEN]),
+            Invalid("v1 header is longer than 107 bytes")
+        );
+        assert_eq!(parse(&longer.as_bytes()[..V1_MAX_LEN - 1]), Incomplete);
+    }
+
+    #[test]
+    fn v1_malformed() {
+        for buffer in [
+            &b"PROXY TCP4 1.1.1.1 2.2.2.2 01 2\r\n"[..],
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 +1 2\r\n",
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 -1 2\r\n",
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 1 65536\r\n",
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 1  2\r\n",
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 1 2 3\r\n",
+            b"PROXY TCP4 1.1.1.1 2.2.2.2 1\r\n",
+            b"PROXY TCP4 ::1 ::2 1 2\r\n",
+            b"PROXY TCP6 1.1.1.1 2.2.2.2 1 2\r\n",
+            b"PROXY TCP4  1.1.1.1 2.2.2.2 1 2\r\n",
+        ] {
+            assert!(
+                matches!(parse(buffer), Invalid(_)),
+                "{}",
+                String::from_utf8_lossy(buffer)
+            );
+        }
+        assert_eq!(
+            parse(b"PROXY UDP4 1.1.1.1 2.2.2.2 1 2\r\n"),
+            Invalid("unknown v1 protocol")
+        );
+        assert_eq!(
+            parse(b"PROXY \xff\r\n"),
+            Invalid("v1 header is not valid ASCII")
+        );
+        // a lone 0 is fine
+        assert_eq!(
+            complete(b"PROXY TCP4 1.1.1.1 2.2.2.2 0 0\r\n").1.addresses,
+            inet("1.1.1.1:0", "2.2.2.2:0")
+        );
+    }
+
+    #[test]
+    fn v2_inet() {
+        let mut body = vec![127, 0, 0, 1, 10, 0, 0, 1];
+        body.extend(56324u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        let buffer = [v2(0x21, 0x11, &body), b"payload".to_vec()].concat();
+        let (len, header) = complete(&buffer);
+        assert_eq!(len, buffer.len() - 7);
+        assert_eq!(header.version, 2);
+        assert_eq!(header.command, Command::Proxy);
+        assert_eq!(header.transport, Transport::Stream);
+        assert_eq!(header.addresses, inet("127.0.0.1:56324", "10.0.0.1:443"));
+
+        let mut body = [0u8; 36];
+        body[15] = 1;
+        body[31] = 2;
+        body[32..].copy_from_slice(&[0, 1, 0, 2]);
+        let buffer = v2(0x21, 0x22, &body);
+        let (_, header) = complete(&buffer);
+        assert_eq!(header.transport, Transport::Dgram);
+        assert_eq!(header.addresses, inet("[::1]:1", "[::2]:2"));
+    }
+
+    #[test]
+    fn v2_unix() {
+        let mut body = vec![0u8; 2 * UNIX_PATH_LEN];
+        body[..4].copy_from_slice(b"/src");
+        body[UNIX_PATH_LEN..UNIX_PATH_LEN + 4].copy_from_slice(b"/dst");
+        let buffer = v2(0x21, 0x31, &body);
+        let (_, header) = complete(&buffer);
+        match header.addresses {
+            Addresses::Unix { src, dst } => {
+                assert_eq!(src.len(), UNIX_PATH_LEN);
+                assert!(src.starts_with(b"/src\0"));
+                assert!(dst.starts_with(b"/dst\0"));
+            }
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn v2_unspec_and_local() {
+        let buffer = v2(0x21, 0x00, &[]);
+        let (_, header) = complete(&buffer);
+        assert_eq!(header.addresses, Addresses::Unspec);
+        assert_eq!(header.transport, Transport::Unspec);
+
+        // the addresses of a LOCAL header are skipped, the TLVs after them aren't
+        let body = [&[1u8; 12][..], &[tlv::PP2_TYPE_NOOP, 0, 0]].concat();
+        let buffer = v2(0x20, 0x11, &body);
+        let (len, header) = complete(&buffer);
+        assert_eq!(len, V2_PREAMBLE_LEN + 15);
+        assert_eq!(header.command, Command::Local);
+        assert_eq!(header.addresses, Addresses::Unspec);
+        assert_eq!(header.tlvs, &[tlv::PP2_TYPE_NOOP, 0, 0]);
+    }
+
+    #[test]
+    fn v2_incomplete() {
+        let buffer = v2(0x21, 0x11, &[0; 12]);
+        for len in V2_SIGNATURE.len()..buffer.len() {
+            assert_eq!(parse(&buffer[..len]), Incomplete, "{len}");
+        }
+    }
+
+    #[test]
+    fn v2_malformed() {
+        assert_eq!(
+            parse(&v2(0x11, 0x11, &[0; 12])),
+            Invalid("unsupported v2 header version")
+        );
+        assert_eq!(
+            parse(&v2(0x22, 0x11, &[0; 12])),
+            Invalid("unknown v2 command")
+        );
+        assert_eq!(
+            parse(&v2(0x21, 0x13, &[0; 12])),
+            Invalid("unknown v2 transport protocol")
+        );
+        assert_eq!(
+            parse(&v2(0x21, 0x41, &[0; 12])),
+            Invalid("unknown v2 address family")
+        );
+        for (family, len) in [(0x11, 11), (0x21, 35), (0x31, 2 * UNIX_PATH_LEN - 1)] {
+            assert_eq!(
+                parse(&v2(0x21, family, &vec![0; len])),
+                Invalid("v2 address block is truncated")
+            );
+        }
+        let body = [&[0u8; 12][..], &[tlv::PP2_TYPE_ALPN, 0, 2, b'h']].concat();
+        assert_eq!(parse(&v2(0x21, 0x11, &body)), Invalid("malformed v2 TLVs"));
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        for addresses in [
+            inet("192.168.0.1:56324", "192.168.0.11:443"),
+            inet("[2001:db8::1]:1", "[2001:db8::2]:2"),
+            Addresses::Unspec,
+        ] {
+            let encoded = encode_v1(&addresses);
+            assert_eq!(
+                complete(&encoded),
+                (encoded.len(), {
+                    Header {
+                        version: 1,
+                        command: Command::Proxy,
+                        transport: Transport::Stream,
+                        addresses,
+                        tlvs: &[],
+                    }
+                })
+            );
+        }
+        // unix sockets can't be sent in v1
+        let unix = Addresses::Unix {
+            src: b"/a",
+            dst: b"/b",
+        };
+        assert_eq!(encode_v1(&unix), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let tlvs = [tlv::PP2_TYPE_AUTHORITY, 0, 3, b'a', b'.', b'b'];
+        let mut src = [0u8; UNIX_PATH_LEN];
+        let mut dst = [0u8; UNIX_PATH_LEN];
+        src[..2].copy_from_slice(b"/a");
+        dst[..2].copy_from_slice(b"/b");
+
+        for addresses in [
+            inet("192.168.0.1:56324", "192.168.0.11:443"),
+            inet("[2001:db8::1]:1", "[2001:db8::2]:2"),
+            Addresses::Unix {
+                src: &src,
+                dst: &dst,
+            },
+            Addresses::Unspec,
+        ] {
+            for transport in [Transport::Stream, Transport::Dgram] {
+                let encoded = encode_v2(Command::Proxy, transport, &addresses, &tlvs);
+                let (len, header) = complete(&encoded);
+                assert_eq!(len, encoded.len());
+                assert_eq!(header.version, 2);
+                assert_eq!(header.command, Command::Proxy);
+                assert_eq!(header.transport, transport);
+                assert_eq!(header.addresses, addresses);
+                assert_eq!(header.tlvs, tlvs);
+            }
+        }
+
+        let encoded = encode_v2(Command::Local, Transport::Unspec, &Addresses::Unspec, &[]);
+        assert_eq!(complete(&encoded).1.command, Command::Local);
+    }
+
+    #[test]
+    fn mixed_families_round_trip() {
+        for (addresses, expected) in [
+            (
+                inet("192.168.0.1:1", "[2001:db8::2]:2"),
+                inet("[::ffff:192.168.0.1]:1", "[2001:db8::2]:2"),
+            ),
+            (
+                inet("[2001:db8::2]:2", "192.168.0.1:1"),
+                inet("[2001:db8::2]:2", "[::ffff:192.168.0.1]:1"),
+            ),
+        ] {
+            let encoded = encode_v2(Command::Proxy, Transport::Stream, &addresses, &[]);
+            assert_eq!(encoded.len(), V2_PREAMBLE_LEN + 36);
+            assert_eq!(complete(&encoded).1.addresses, expected);
+
+            let encoded = encode_v1(&addresses);
+            assert!(encoded.starts_with(b"PROXY TCP6 "));
+            assert_eq!(complete(&encoded).1.addresses, expected);
+        }
+    }
+
+    #[test]
+    fn v2_tlvs_that_dont_fit() {
+        let addresses = inet("[::1]:1", "[::2]:2");
+        let max = max_v2_tlvs_len(&addresses);
+        assert_eq!(max, u16::MAX as usize - 36);
+
+        let mut tlvs = vec![tlv::PP2_TYPE_NOOP];
+        tlvs.extend(((max - 3) as u16).to_be_bytes());
+        tlvs.resize(max, 0);
+        let encoded = encode_v2(Command::Proxy, Transport::Stream, &addresses, &tlvs);
+        assert_eq!(encoded.len(), MAX_HEADER_LEN);
+        assert_eq!(complete(&encoded).1.tlvs.len(), max);
+
+        tlvs.push(0);
+        let encoded = encode_v2(Command::Proxy, Transport::Stream, &addresses, &tlvs);
+        assert_eq!(encoded.len(), V2_PREAMBLE_LEN + 36);
+        let (_, header) = complete(&encoded);
+        assert_eq!(header.addresses, addresses);
+        assert!(header.tlvs.is_empty());
+    }
+}
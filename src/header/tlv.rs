@@ -0,0 +1,462 @@
+use std::{fmt, io};
+
+pub const PP2_TYPE_ALPN: u8 = 0x01;
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+pub const PP2_TYPE_CRC32C: u8 = 0x03;
+pub const PP2_TYPE_NOOP: u8 = 0x04;
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+pub const PP2_TYPE_SSL: u8 = 0x20;
+pub const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+pub const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+pub const PP2_SUBTYPE_SSL_CIPHER: u8 = 0x23;
+pub const PP2_SUBTYPE_SSL_SIG_ALG: u8 = 0x24;
+pub const PP2_SUBTYPE_SSL_KEY_ALG: u8 = 0x25;
+pub const PP2_TYPE_NETNS: u8 = 0x30;
+// vendor specific types, see the AWS, Azure and GCP load balancer docs
+pub const PP2_TYPE_GCP: u8 = 0xE0;
+pub const PP2_TYPE_AWS: u8 = 0xEA;
+pub const PP2_TYPE_AZURE: u8 = 0xEE;
+pub const PP2_SUBTYPE_AWS_VPCE_ID: u8 = 0x01;
+pub const PP2_SUBTYPE_AZURE_PRIVATEENDPOINT_LINKID: u8 = 0x01;
+
+// the spec limits PP2_TYPE_UNIQUE_ID values to 128 bytes
+const UNIQUE_ID_MAX_LEN: usize = 128;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Ssl {
+    pub client: u8,
+    pub verify: u32,
+    pub version: Option<String>,
+    pub cn: Option<String>,
+    pub cipher: Option<String>,
+    pub sig_alg: Option<String>,
+    pub key_alg: Option<String>,
+}
+
+impl Ssl {
+    // PP2_CLIENT_SSL
+    pub fn is_ssl(&self) -> bool {
+        self.client & 0x01 != 0
+    }
+}
+
+// the TLVs carried in a v2 header, decoded into the fields we know about
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tlvs {
+    pub alpn: Option<Vec<u8>>,
+    pub authority: Option<String>,
+    pub crc32c: Option<u32>,
+    pub unique_id: Option<Vec<u8>>,
+    pub ssl: Option<Ssl>,
+    pub netns: Option<String>,
+    pub aws_vpce_id: Option<String>,
+    pub azure_link_id: Option<u32>,
+    pub gcp_psc_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub kind: u8,
+    pub value: &'a [u8],
+}
+
+// walks a raw TLV block without copying it, stops after the first malformed TLV
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    buffer: &'a [u8],
+}
+
+pub fn iter(buffer: &[u8]) -> Iter<'_> {
+    Iter { buffer }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<Tlv<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        if self.buffer.len() < 3 {
+            self.buffer = &[];
+            return Some(Err("truncated TLV"));
+        }
+
+        let len = u16::from_be_bytes([self.buffer[1], self.buffer[2]]) as usize;
+        if self.buffer.len() < 3 + len {
+            self.buffer = &[];
+            return Some(Err("TLV value overflows the header"));
+        }
+
+        let (tlv, rest) = self.buffer.split_at(3 + len);
+        self.buffer = rest;
+
+        Some(Ok(Tlv {
+            kind: tlv[0],
+            value: &tlv[3..],
+        }))
+    }
+}
+
+impl Tlvs {
+    // a malformed TLV block fails, a value that can't be decoded is logged and
+    // left out, like the TLVs we don't know; only the checksum has to be valid,
+    // otherwise a broken one would go unchecked
+    pub fn parse(buffer: &[u8]) -> io::Result<Self> {
+        let mut tlvs = Self::default();
+
+        for tlv in iter(buffer) {
+            let Tlv { kind, value } = tlv.map_err(io::Error::other)?;
+
+            match kind {
+                PP2_TYPE_ALPN => tlvs.alpn = Some(value.to_vec()),
+                PP2_TYPE_AUTHORITY => tlvs.authority = decoded(utf8(kind, value)),
+                // only the first one would be checked
+                PP2_TYPE_CRC32C if tlvs.crc32c.is_some() => {
+                    return Err(invalid(kind, "the header has more than one checksum"));
+                }
+                PP2_TYPE_CRC32C => tlvs.crc32c = Some(u32::from_be_bytes(array(kind, value)?)),
+                PP2_TYPE_UNIQUE_ID => {
+                    tlvs.unique_id = decoded(match value.len() {
+                        len if len > UNIQUE_ID_MAX_LEN => {
+                            Err(invalid(kind, "value is longer than 128 bytes"))
+                        }
+                        _ => Ok(value.to_vec()),
+                    })
+                }
+                PP2_TYPE_SSL => tlvs.ssl = decoded(parse_ssl(value)),
+                PP2_TYPE_NETNS => tlvs.netns = decoded(utf8(kind, value)),
+                PP2_TYPE_AWS => match value.split_first() {
+                    Some((&PP2_SUBTYPE_AWS_VPCE_ID, id)) => {
+                        tlvs.aws_vpce_id = decoded(utf8(kind, id))
+                    }
+                    _ => log::debug!("ignoring unknown AWS TLV subtype"),
+                },
+                PP2_TYPE_AZURE => match value.split_first() {
+                    Some((&PP2_SUBTYPE_AZURE_PRIVATEENDPOINT_LINKID, id)) => {
+                        tlvs.azure_link_id = decoded(array(kind, id).map(u32::from_le_bytes))
+                    }
+                    _ => log::debug!("ignoring unknown Azure TLV subtype"),
+                },
+                PP2_TYPE_GCP => {
+                    tlvs.gcp_psc_id = decoded(array(kind, value).map(u64::from_be_bytes))
+                }
+                PP2_TYPE_NOOP => {}
+                _ => log::debug!("ignoring unknown TLV type {kind:#04x}"),
+            }
+        }
+
+        Ok(tlvs)
+    }
+}
+
+// returns the offset of the first `kind` TLV's value within the buffer
+pub fn value_offset(buffer: &[u8], kind: u8) -> Option<usize> {
+    let mut offset = 0;
+
+    for tlv in iter(buffer) {
+        let tlv = tlv.ok()?;
+        if tlv.kind == kind {
+            return Some(offset + 3);
+        }
+        offset += 3 + tlv.value.len();
+    }
+
+    None
+}
+
+// renders the fields that are useful in the connection logs
+impl fmt::Display for Tlvs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref authority) = self.authority {
+            write!(f, " [authority: {authority}]")?;
+        }
+        if let Some(ref alpn) = self.alpn {
+            write!(f, " [alpn: {}]", String::from_utf8_lossy(alpn))?;
+        }
+        if let Some(ssl) = self.ssl.as_ref().filter(|ssl| ssl.is_ssl()) {
+            if let Some(ref version) = ssl.version {
+                write!(f, " [ssl: {version}]")?;
+            }
+            if let Some(ref cn) = ssl.cn {
+                write!(f, " [cn: {cn}]")?;
+            }
+        }
+        if let Some(ref id) = self.aws_vpce_id {
+            write!(f, " [vpce: {id}]")?;
+        }
+        if let Some(id) = self.azure_link_id {
+            write!(f, " [linkid: {id}]")?;
+        }
+        if let Some(id) = self.gcp_psc_id {
+            write!(f, " [psc: {id}]")?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_ssl(value: &[u8]) -> io::Result<Ssl> {
+    if value.len() < 5 {
+        return Err(invalid(PP2_TYPE_SSL, "value is shorter than 5 bytes"));
+    }
+
+    let mut ssl = Ssl {
+        client: value[0],
+        verify: u32::from_be_bytes([value[1], value[2], value[3], value[4]]),
+        ..Default::default()
+    };
+
+    for tlv in iter(&value[5..]) {
+        let Tlv { kind, value } = tlv.map_err(|why| invalid(PP2_TYPE_SSL, why))?;
+
+        let field = match kind {
+            PP2_SUBTYPE_SSL_VERSION => &mut ssl.version,
+            PP2_SUBTYPE_SSL_CN => &mut ssl.cn,
+            PP2_SUBTYPE_SSL_CIPHER => &mut ssl.cipher,
+            PP2_SUBTYPE_SSL_SIG_ALG => &mut ssl.sig_alg,
+            PP2_SUBTYPE_SSL_KEY_ALG => &mut ssl.key_alg,
+            _ => {
+                log::debug!("ignoring unknown SSL sub-TLV type {kind:#04x}");
+                continue;
+            }
+        };
+        *field = decoded(utf8(kind, value));
+    }
+
+    Ok(ssl)
+}
+
+fn decoded<T>(value: io::Result<T>) -> Option<T> {
+    value.map_err(|why| log::warn!("ignoring TLV: {why}")).ok()
+}
+
+fn utf8(kind: u8, value: &[u8]) -> io::Result<String> {
+    String::from_utf8(value.to_vec()).map_err(|_| invalid(kind, "value is not valid UTF-8"))
+}
+
+fn array<const N: usize>(kind: u8, value: &[u8]) -> io::Result<[u8; N]> {
+    value
+        .try_into()
+        .map_err(|_| invalid(kind, &format!("value is not {N} bytes long")))
+}
+
+fn invalid(kind: u8, why: &str) -> io::Error {
+    io::Error::other(format!("invalid TLV {kind:#04x}: {why}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tlv(kind: u8, value: &[u8]) -> Vec<u8> {
+        let mut tlv = vec![kind];
+        tlv.extend((value.len() as u16).to_be_bytes());
+        tlv.extend(value);
+        tlv
+    }
+
+    #[test]
+    fn iterates() {
+        let buffer = [tlv(PP2_TYPE_ALPN, b"h2"), tlv(PP2_TYPE_NOOP, b"")].concat();
+        let tlvs: Vec<_> = iter(&buffer).collect();
+        assert_eq!(
+            tlvs,
+            [
+                Ok(Tlv {
+                    kind: PP2_TYPE_ALPN,
+                    value: b"h2"
+                }),
+                Ok(Tlv {
+                    kind: PP2_TYPE_NOOP,
+                    value: b""
+                }),
+            ]
+        );
+        assert_eq!(iter(&[]).count(), 0);
+    }
+
+    #[test]
+    fn iter_stops_at_malformed_tlvs() {
+        for (buffer, why) in [
+            (&[PP2_TYPE_ALPN][..], "truncated TLV"),
+            (&[PP2_TYPE_ALPN, 0], "truncated TLV"),
+            (&[PP2_TYPE_ALPN, 0, 1], "TLV value overflows the header"),
+            (
+                &[PP2_TYPE_ALPN, 0xff, 0xff, 0],
+                "TLV value overflows the header",
+            ),
+        ] {
+            let mut tlvs = iter(buffer);
+            assert_eq!(tlvs.next(), Some(Err(why)));
+            assert_eq!(tlvs.next(), None);
+        }
+
+        let buffer = [tlv(PP2_TYPE_ALPN, b"h2"), vec![PP2_TYPE_NOOP, 0]].concat();
+        let tlvs: Vec<_> = iter(&buffer).collect();
+        assert!(tlvs[0].is_ok());
+        assert_eq!(tlvs[1], Err("truncated TLV"));
+        assert_eq!(tlvs.len(), 2);
+    }
+
+    #[test]
+    fn parses_known_types() {
+        let buffer = [
+            tlv(PP2_TYPE_ALPN, b"h2"),
+            tlv(PP2_TYPE_AUTHORITY, b"example.com"),
+            tlv(PP2_TYPE_CRC32C, &[0xde, 0xad, 0xbe, 0xef]),
+            tlv(PP2_TYPE_NOOP, &[0; 3]),
+            tlv(PP2_TYPE_UNIQUE_ID, &[7; UNIQUE_ID_MAX_LEN]),
+            tlv(PP2_TYPE_NETNS, b"blue"),
+            tlv(0x50, b"unknown"),
+        ]
+        .concat();
+
+        let tlvs = Tlvs::parse(&buffer).unwrap();
+        assert_eq!(
+            tlvs,
+            Tlvs {
+                alpn: Some(b"h2".to_vec()),
+                authority: Some("example.com".to_owned()),
+                crc32c: Some(0xdeadbeef),
+                unique_id: Some(vec![7; UNIQUE_ID_MAX_LEN]),
+                netns: Some("blue".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(Tlvs::parse(&[]).unwrap(), Tlvs::default());
+    }
+
+    #[test]
+    fn parses_ssl() {
+        let mut value = vec![0x05, 0, 0, 0, 1];
+        value.extend(tlv(PP2_SUBTYPE_SSL_VERSION, b"TLSv1.3"));
+        value.extend(tlv(PP2_SUBTYPE_SSL_CN, b"client"));
+        value.extend(tlv(PP2_SUBTYPE_SSL_CIPHER, b"TLS_AES_128_GCM_SHA256"));
+        value.extend(tlv(PP2_SUBTYPE_SSL_SIG_ALG, b"RSA-SHA256"));
+        value.extend(tlv(PP2_SUBTYPE_SSL_KEY_ALG, b"RSA2048"));
+        value.extend(tlv(0x2f, b"unknown"));
+
+        let tlvs = Tlvs::parse(&tlv(PP2_TYPE_SSL, &value)).unwrap();
+        let ssl = tlvs.ssl.as_ref().unwrap();
+        assert!(ssl.is_ssl());
+        assert_eq!(
+            *ssl,
+            Ssl {
+                client: 0x05,
+                verify: 1,
+                version: Some("TLSv1.3".to_owned()),
+                cn: Some("client".to_owned()),
+                cipher: Some("TLS_AES_128_GCM_SHA256".to_owned()),
+                sig_alg: Some("RSA-SHA256".to_owned()),
+                key_alg: Some("RSA2048".to_owned()),
+            }
+        );
+        assert_eq!(tlvs.to_string(), " [ssl: TLSv1.3] [cn: client]");
+
+        // the SSL fields of a connection that wasn't made over SSL aren't logged
+        let tlvs = Tlvs::parse(&tlv(PP2_TYPE_SSL, &[0x00, 0, 0, 0, 0])).unwrap();
+        assert!(!tlvs.ssl.as_ref().unwrap().is_ssl());
+        assert_eq!(tlvs.to_string(), "");
+    }
+
+    #[test]
+    fn parses_vendor_types() {
+        let buffer = [
+            tlv(PP2_TYPE_AWS, b"\x01vpce-08d2bf15fac5001c9"),
+            tlv(PP2_TYPE_AZURE, &[0x01, 0x78, 0x56, 0x34, 0x12]),
+            tlv(PP2_TYPE_GCP, &[0, 0, 0, 0, 0, 0, 0x30, 0x39]),
+        ]
+        .concat();
+
+        let tlvs = Tlvs::parse(&buffer).unwrap();
+        assert_eq!(tlvs.aws_vpce_id.as_deref(), Some("vpce-08d2bf15fac5001c9"));
+        assert_eq!(tlvs.azure_link_id, Some(0x12345678));
+        assert_eq!(tlvs.gcp_psc_id, Some(12345));
+        assert_eq!(
+            tlvs.to_string(),
+            " [vpce: vpce-08d2bf15fac5001c9] [linkid: 305419896] [psc: 12345]"
+        );
+
+        // unknown subtypes are skipped
+        let buffer = [tlv(PP2_TYPE_AWS, b"\x02x"), tlv(PP2_TYPE_AZURE, b"")].concat();
+        assert_eq!(Tlvs::parse(&buffer).unwrap(), Tlvs::default());
+    }
+
+    #[test]
+    fn finds_value_offset() {
+        let buffer = [
+            tlv(PP2_TYPE_ALPN, b"h2"),
+            tlv(PP2_TYPE_CRC32C, &[0; 4]),
+            tlv(PP2_TYPE_CRC32C, &[0; 4]),
+        ]
+        .concat();
+        assert_eq!(value_offset(&buffer, PP2_TYPE_ALPN), Some(3));
+        assert_eq!(value_offset(&buffer, PP2_TYPE_CRC32C), Some(8));
+        assert_eq!(value_offset(&buffer, PP2_TYPE_SSL), None);
+        assert_eq!(value_offset(&buffer[..10], PP2_TYPE_CRC32C), None);
+    }
+
+    #[test]
+    fn undecodable_values_are_left_out() {
+        let mut ssl = vec![0x01, 0, 0, 0, 0];
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_VERSION, b"TLSv1.3"));
+        ssl.extend(tlv(PP2_SUBTYPE_SSL_CN, b"\xff"));
+
+        let buffer = [
+            tlv(PP2_TYPE_AUTHORITY, b"\xffexample.com"),
+            tlv(PP2_TYPE_ALPN, b"h2"),
+            tlv(PP2_TYPE_UNIQUE_ID, &[0; UNIQUE_ID_MAX_LEN + 1]),
+            tlv(PP2_TYPE_GCP, &[0; 4]),
+            tlv(
+                PP2_TYPE_AZURE,
+                &[PP2_SUBTYPE_AZURE_PRIVATEENDPOINT_LINKID, 1],
+            ),
+            tlv(PP2_TYPE_SSL, &ssl),
+        ]
+        .concat();
+
+        let tlvs = Tlvs::parse(&buffer).unwrap();
+        assert_eq!(tlvs.authority, None);
+        assert_eq!(tlvs.alpn.as_deref(), Some(&b"h2"[..]));
+        assert_eq!(tlvs.unique_id, None);
+        assert_eq!(tlvs.gcp_psc_id, None);
+        assert_eq!(tlvs.azure_link_id, None);
+        let ssl = tlvs.ssl.unwrap();
+        assert_eq!(ssl.version.as_deref(), Some("TLSv1.3"));
+        assert_eq!(ssl.cn, None);
+    }
+
+    #[test]
+    fn malformed_ssl_value_is_left_out() {
+        let buffer = [
+            tlv(PP2_TYPE_SSL, &[0x01, 0, 0]),
+            tlv(PP2_TYPE_NETNS, b"blue"),
+        ]
+        .concat();
+        let tlvs = Tlvs::parse(&buffer).unwrap();
+        assert_eq!(tlvs.ssl, None);
+        assert_eq!(tlvs.netns.as_deref(), Some("blue"));
+
+        // sub-TLVs that overflow the SSL value
+        let buffer = tlv(PP2_TYPE_SSL, &[0x01, 0, 0, 0, 0, PP2_SUBTYPE_SSL_CN, 0, 9]);
+        assert_eq!(Tlvs::parse(&buffer).unwrap().ssl, None);
+    }
+
+    #[test]
+    fn malformed_block_fails() {
+        assert!(Tlvs::parse(&[PP2_TYPE_ALPN, 0]).is_err());
+        assert!(Tlvs::parse(&[PP2_TYPE_ALPN, 0, 3, b'h', b'2']).is_err());
+    }
+
+    #[test]
+    fn malformed_checksum_fails() {
+        assert!(Tlvs::parse(&tlv(PP2_TYPE_CRC32C, &[0; 3])).is_err());
+    }
+
+    #[test]
+    fn duplicate_checksum_fails() {
+        let buffer = [tlv(PP2_TYPE_CRC32C, &[0; 4]), tlv(PP2_TYPE_CRC32C, &[0; 4])].concat();
+        assert!(Tlvs::parse(&buffer).is_err());
+    }
+}
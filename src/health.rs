@@ -0,0 +1,297 @@
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::{
+    args::SharedArgs,
+    metrics,
+    pool::{Backend, Pool},
+    util::{self, Protocol, Upstream},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UnixStream,
+    time::MissedTickBehavior,
+};
+
+// how the upstreams are probed, taken from the arguments
+#[derive(Debug)]
+struct Check {
+    interval: Duration,
+    timeout: Duration,
+    rise: u32,
+    fall: u32,
+    send: Vec<u8>,
+    expect: Vec<u8>,
+}
+
+// the health of an upstream as far as the probes can tell
+#[derive(Debug)]
+struct State {
+    healthy: bool,
+    // probes in a row that disagreed with `healthy`
+    streak: u32,
+}
+
+// probes every upstream of every pool in the background, over the protocol
+// of each listener that uses the pool; probes are sent from our own address,
+// so they work without IP_TRANSPARENT and don't depend on the routing that
+// transparent connections need
+//
+// the health check flags are global, so the first listener's are used
+pub fn spawn(listeners: Vec<SharedArgs>, interval: Duration) {
+    let args = listeners[0].load();
+    let check = Arc::new(Check {
+        interval,
+        timeout: args.health_timeout,
+        rise: args.health_rise.max(1),
+        fall: args.health_fall.max(1),
+        send: args.health_send.clone(),
+        expect: args.health_expect.clone(),
+    });
+
+    log::info!("health checking upstreams every {interval:?}");
+    tokio::spawn(run(listeners, check));
+}
+
+// the pools of a listener change on reload, and the backends of a pool when
+// names are resolved again, so they are collected anew for every round of probes
+async fn run(listeners: Vec<SharedArgs>, check: Arc<Check>) {
+    let mut states = HashMap::<(Protocol, Upstream), State>::new();
+    let mut interval = tokio::time::interval(check.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let pools: Vec<(Protocol, Pool)> = listeners
+            .iter()
+            .flat_map(|args| {
+                let args = args.load();
+                args.pools()
+                    .map(|pool| (args.protocol, pool.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        // an upstream that is in several pools is probed once for all of them,
+        // per protocol
+        let mut upstreams = HashMap::<Upstream, Vec<Arc<Backend>>>::new();
+        let mut probed = HashSet::new();
+        for (protocol, pool) in &pools {
+            for backend in pool.backends() {
+                probed.insert((*protocol, backend.upstream.clone()));
+                upstreams
+                    .entry(backend.upstream.clone())
+                    .or_default()
+                    .push(backend);
+            }
+        }
+        states.retain(|key, _| probed.contains(key));
+        metrics::export_upstreams(upstreams.values().map(|backends| backends[0].clone()));
+
+        let probes: Vec<_> = probed
+            .into_iter()
+            .map(|key| {
+                let check = check.clone();
+                let (protocol, upstream) = key.clone();
+                let probe = tokio::spawn(async move {
+                    let probe = probe(protocol, &upstream, &check);
+                    match tokio::time::timeout(check.timeout, probe).await {
+                        Ok(ret) => ret,
+                        Err(_) => Err(eyre!("timed out after {:?}", check.timeout)),
+                    }
+                });
+                (key, probe)
+            })
+            .collect();
+
+        for (key, probe) in probes {
+            // a probe that panicked counts as a failed one
+            let ret = probe.await.unwrap_or_else(|why| Err(eyre!("{why}")));
+            let state = states.entry(key.clone()).or_insert(State {
+                healthy: true,
+                streak: 0,
+            });
+            update(state, &key, ret, &check);
+        }
+
+        // an upstream that is probed over both protocols has to pass both, and
+        // new backends of a known upstream take its state right away
+        for (upstream, backends) in &upstreams {
+            let healthy = states
+                .iter()
+                .filter(|((_, other), _)| other == upstream)
+                .all(|(_, state)| state.healthy);
+            for backend in backends {
+                backend.set_healthy(healthy);
+            }
+        }
+    }
+}
+
+// takes an upstream out of selection after `fall` failed probes in a row, and
+// puts it back after `rise` passed ones
+fn update(state: &mut State, key: &(Protocol, Upstream), ret: Result<()>, check: &Check) {
+    let (protocol, upstream) = key;
+    if ret.is_ok() == state.healthy {
+        state.streak = 0;
+        return;
+    }
+
+    state.streak += 1;
+    let threshold = if state.healthy {
+        check.fall
+    } else {
+        check.rise
+    };
+    if state.streak < threshold {
+        return;
+    }
+    state.healthy = !state.healthy;
+    state.streak = 0;
+
+    match ret {
+        Ok(()) => log::info!("[health] {protocol} {upstream} is up"),
+        Err(why) => log::warn!("[health] {protocol} {upstream} is down: {why:#}"),
+    }
+}
+
+async fn probe(protocol: Protocol, upstream: &Upstream, check: &Check) -> Result<()> {
+    match (protocol, upstream) {
+        (Protocol::Tcp, Upstream::Inet(addr)) => {
+            probe_stream(util::tcp_create_local_conn(*addr).await?, check).await
+        }
+        (Protocol::Tcp, Upstream::Unix(path)) => {
+            let stream = UnixStream::connect(path)
+                .await
+                .wrap_err_with(|| format!("failed to connect to {upstream}"))?;
+            probe_stream(stream, check).await
+        }
+        (Protocol::Udp, Upstream::Inet(addr)) => {
+            let sock = util::udp_create_local_conn(*addr).await?;
+            sock.send(&check.send).await?;
+
+            let mut buffer = vec![0u8; check.expect.len().max(1)];
+            let read = sock.recv(&mut buffer).await?;
+            expect(&buffer[..read], check)
+        }
+        (Protocol::Udp, Upstream::Unix(path)) => {
+            let sock = util::udp_create_unix_conn(path)?;
+            sock.send(&check.send).await?;
+
+            let mut buffer = vec![0u8; check.expect.len().max(1)];
+            let read = sock.recv(&mut buffer).await?;
+            expect(&buffer[..read], check)
+        }
+    }
+}
+
+// a TCP probe passes once it's connected, unless a reply is expected
+async fn probe_stream<S>(mut stream: S, check: &Check) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if !check.send.is_empty() {
+        stream.write_all(&check.send).await?;
+    }
+
+    let mut buffer = vec![0u8; check.expect.len()];
+    let mut read = 0;
+    while read < buffer.len() {
+        match stream.read(&mut buffer[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    expect(&buffer[..read], check)
+}
+
+fn expect(reply: &[u8], check: &Check) -> Result<()> {
+    if reply.starts_with(&check.expect) {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "unexpected reply: {:?}",
+            String::from_utf8_lossy(reply)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(rise: u32, fall: u32, expect: &[u8]) -> Check {
+        Check {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            rise,
+            fall,
+            send: Vec::new(),
+            expect: expect.to_vec(),
+        }
+    }
+
+    // feeds the probe results to a healthy upstream, returning its health
+    // after each one
+    fn run(check: &Check, probes: &[bool]) -> Vec<bool> {
+        let key = (Protocol::Tcp, "127.0.0.1:80".parse().unwrap());
+        let mut state = State {
+            healthy: true,
+            streak: 0,
+        };
+        probes
+            .iter()
+            .map(|&passed| {
+                let ret = if passed { Ok(()) } else { Err(eyre!("down")) };
+                update(&mut state, &key, ret, check);
+                state.healthy
+            })
+            .collect()
+    }
+
+    #[test]
+    fn falls_after_fall_failures() {
+        let check = check(2, 3, b"");
+        assert_eq!(
+            run(&check, &[false, false, false, false]),
+            [true, true, false, false]
+        );
+        assert_eq!(run(&self::check(1, 1, b""), &[false]), [false]);
+    }
+
+    #[test]
+    fn rises_after_rise_passes() {
+        let check = check(2, 1, b"");
+        assert_eq!(
+            run(&check, &[false, true, true, true]),
+            [false, false, true, true]
+        );
+    }
+
+    #[test]
+    fn agreeing_probes_reset_the_streak() {
+        let check = check(2, 3, b"");
+        assert_eq!(
+            run(&check, &[false, false, true, false, false, false]),
+            [true, true, true, true, true, false]
+        );
+        assert_eq!(
+            run(&check, &[false, false, false, true, false, true, true]),
+            [true, true, false, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn expects_a_prefix() {
+        assert!(expect(b"", &check(1, 1, b"")).is_ok());
+        assert!(expect(b"anything", &check(1, 1, b"")).is_ok());
+        assert!(expect(b"+PONG\r\n", &check(1, 1, b"+PONG")).is_ok());
+        assert!(expect(b"+PON", &check(1, 1, b"+PONG")).is_err());
+        assert!(expect(b"-ERR", &check(1, 1, b"+PONG")).is_err());
+    }
+}
@@ -1,2 +1,178 @@
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::{
+    args::{Args, SharedArgs},
+    route::Target,
+    shutdown,
+    tls::ClientHello,
+    upgrade,
+    util::{self, DialError, Upstream},
+};
+use socket2::Socket;
+use std::{future::Future, net::SocketAddr, os::fd::AsFd, thread};
+use tokio::{runtime, sync::mpsc};
+
 pub mod tcp;
 pub mod udp;
+
+// the sockets of a listener: the `activated` ones, if any, or else as many as
+// --listeners of its own, made by `bind`; a hot upgrade hands them over to the
+// new process
+pub(crate) fn sockets<S>(
+    args: &Args,
+    activated: Vec<Socket>,
+    bind: impl Fn(&Args) -> Result<S>,
+) -> Result<Vec<S>>
+where
+    S: From<Socket> + AsFd,
+{
+    let sockets = if activated.is_empty() {
+        (0..args.listeners)
+            .map(|_| bind(args))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        log::info!("using {} activated socket(s)", activated.len());
+        activated
+            .into_iter()
+            .map(|socket| {
+                socket
+                    .set_nonblocking(true)
+                    .wrap_err("failed to set nonblocking on the activated socket")?;
+                Ok(socket.into())
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    for socket in &sockets {
+        upgrade::register(args.fd_name.as_deref(), socket.as_fd())
+            .wrap_err("failed to keep the listening socket for upgrades")?;
+    }
+    Ok(sockets)
+}
+
+// runs `serve` on every socket of a listener, each in a loop of its own: as
+// tasks of the main runtime, or with --pin-listeners on threads of their own,
+// each with a current-thread runtime pinned to a CPU
+//
+// a loop that fails is reported, the listener fails with the last one
+pub(crate) async fn serve_sockets<S, F, Fut>(
+    args: SharedArgs,
+    sockets: Vec<S>,
+    serve: F,
+) -> Result<()>
+where
+    S: Send + 'static,
+    F: FnOnce(SharedArgs, S) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut left = sockets.len();
+    let (pin_listeners, protocol) = {
+        let args = args.load();
+        (args.pin_listeners, args.protocol)
+    };
+    let (tx, mut rx) = mpsc::unbounded_channel();
+
+    for (n, socket) in sockets.into_iter().enumerate() {
+        let (args, serve, tx) = (args.clone(), serve.clone(), tx.clone());
+        if !pin_listeners {
+            tokio::spawn(async move {
+                let _ = tx.send(serve(args, socket).await);
+            });
+            continue;
+        }
+
+        thread::Builder::new()
+            .name(format!("{protocol}-listener-{n}"))
+            .spawn(move || {
+                match util::pin_to_cpu(n) {
+                    Ok(cpu) => log::debug!("{protocol} listener thread {n} pinned to CPU {cpu}"),
+                    Err(why) => log::warn!("failed to pin {protocol} listener thread {n}: {why}"),
+                }
+                let runtime = match runtime::Builder::new_current_thread().enable_all().build() {
+                    Ok(runtime) => runtime,
+                    Err(why) => {
+                        let _ = tx.send(Err(why).wrap_err("failed to start the listener runtime"));
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    let _ = tx.send(serve(args, socket).await);
+                    drop(tx);
+                    // the connections of this thread are dropped with its runtime
+                    shutdown::drained().await;
+                });
+            })
+            .wrap_err("failed to start a listener thread")?;
+    }
+    drop(tx);
+
+    let mut last = Ok(());
+    while let Some(ret) = rx.recv().await {
+        left -= 1;
+        match ret {
+            Err(why) if left > 0 => log::error!("{why:#}"),
+            ret => last = ret,
+        }
+    }
+    last
+}
+
+// dials the upstream of a new connection with `connect`, which gets the
+// upstream and whether it's reached transparently; when that fails, up to
+// --retries other upstreams are tried before the connection is given up
+//
+// `hello` is the TLS ClientHello peeked from the client, if any, which the
+// sni and alpn routes match on
+//
+// every failed attempt is counted by why it failed
+pub(crate) async fn connect_upstream<'a, T, F, Fut>(
+    args: &'a Args,
+    src: &SocketAddr,
+    dst: &SocketAddr,
+    hello: Option<&ClientHello>,
+    mut connect: F,
+) -> Result<(Target<'a>, T)>
+where
+    F: FnMut(Upstream, bool) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut tried = Vec::new();
+
+    loop {
+        let target = args.upstream_for(src, dst, hello, &tried)?;
+        let upstream = target.lease.upstream().clone();
+        let transparent = target.reemit().is_none();
+
+        let dial = connect(upstream.clone(), transparent);
+        let ret = match args.connect_timeout {
+            Some(deadline) => match tokio::time::timeout(deadline, dial).await {
+                Ok(ret) => ret.map_err(|why| (DialError::classify(&why), why)),
+                Err(_) => Err((DialError::Timeout, eyre!("timed out after {deadline:?}"))),
+            },
+            None => dial.await.map_err(|why| (DialError::classify(&why), why)),
+        };
+        let why = match ret {
+            Ok(conn) => return Ok((target, conn)),
+            Err((class, why)) => {
+                class.counter().inc();
+                why.wrap_err(class.message())
+            }
+        };
+        if args.retries == 0 {
+            return Err(why);
+        }
+
+        let attempt = tried.len() as u32 + 1;
+        if attempt > args.retries {
+            return Err(why).wrap_err_with(|| format!("giving up after {attempt} attempts"));
+        }
+        log::warn!("[attempt {attempt}] {upstream}: {why:#}");
+        tried.push(upstream);
+
+        // the connection's lease is released while waiting
+        drop(target);
+        if let Some(backoff) = args.retry_backoff {
+            tokio::time::sleep(backoff.saturating_mul(2u32.saturating_pow(attempt - 1))).await;
+        }
+    }
+}
@@ -1,47 +1,82 @@
-use simple_eyre::eyre::{Result, WrapErr};
+use simple_eyre::eyre::{eyre, Result, WrapErr};
 
 use crate::{
-    args::Args,
+    args::{Args, SharedArgs},
+    header::{self, Addresses, Command, ParseResult, Transport},
+    listener, metrics,
     pipe::{splice, wouldblock, Pipe, PIPE_BUF_SIZE},
-    util,
+    shutdown,
+    tls::{self, ClientHello, Peek},
+    util::{self, HeaderPolicy, LocalAction, Upstream},
 };
 
-use std::{net::SocketAddr, os::fd::AsRawFd};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::{
+    io,
+    net::{Shutdown, SocketAddr},
+    os::fd::AsRawFd,
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, Interest},
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        TcpSocket, TcpStream,
-    },
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest},
+    net::{TcpListener, TcpStream, UnixStream},
 };
 
-pub async fn listen(args: Args) -> Result<()> {
-    let socket = match args.listen_addr {
-        SocketAddr::V4(_) => TcpSocket::new_v4(),
-        SocketAddr::V6(_) => TcpSocket::new_v6(),
-    };
-    let socket = socket.wrap_err("failed to create socket")?;
+// listens on the `activated` sockets, if any, or else binds its own
+pub async fn listen(shared: SharedArgs, activated: Vec<Socket>) -> Result<()> {
+    let args = shared.load_full();
+    let sockets = listener::sockets(&args, activated, bind)?;
+    if args.defer_accept > 0 {
+        log::info!(
+            "TCP_DEFER_ACCEPT: idle connections are held back by the kernel for {}s",
+            args.defer_accept
+        );
+    }
+
+    log::info!("listening on: {}", args.listen_addr);
+    listener::serve_sockets(shared, sockets, accept_loop).await
+}
+
+// every socket of a listener is bound on its own, the kernel spreads the
+// connections over them with SO_REUSEPORT
+fn bind(args: &Args) -> Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(args.listen_addr), Type::STREAM, None)
+        .wrap_err("failed to create socket")?;
 
     socket
-        .set_reuseport(args.listeners > 1)
+        .set_reuse_port(args.listeners > 1)
         .wrap_err("failed to set reuseport")?;
     socket
-        .set_reuseaddr(true)
+        .set_reuse_address(true)
         .wrap_err("failed to set reuseaddr")?;
     socket
-        .bind(args.listen_addr)
+        .set_nonblocking(true)
+        .wrap_err("failed to set nonblocking")?;
+    socket
+        .bind(&args.listen_addr.into())
         .wrap_err_with(|| format!("failed to bind to {}", args.listen_addr))?;
-
-    let listener = socket
-        .listen(args.listeners)
+    if args.defer_accept > 0 {
+        util::set_defer_accept(socket.as_raw_fd(), args.defer_accept)
+            .wrap_err("failed to set TCP_DEFER_ACCEPT")?;
+    }
+    socket
+        .listen(args.backlog.min(i32::MAX as u32) as i32)
         .wrap_err("failed to start the listener")?;
 
-    log::info!("listening on: {}", args.listen_addr);
+    Ok(socket.into())
+}
+
+async fn accept_loop(shared: SharedArgs, listener: std::net::TcpListener) -> Result<()> {
+    let listener = TcpListener::from_std(listener).wrap_err("failed to register the listener")?;
+
     loop {
-        let (conn, addr) = listener
-            .accept()
-            .await
-            .wrap_err("failed to accept connection")?;
+        let (conn, addr) = tokio::select! {
+            ret = listener.accept() => ret.wrap_err("failed to accept connection")?,
+            // connections that were already accepted keep going on their own
+            _ = shutdown::stopped() => return Ok(()),
+        };
+        // a reload only applies to the connections accepted after it
+        let args = shared.load_full();
 
         if let Some(ref allowed_subnets) = args.allowed_subnets {
             let ip_addr = addr.ip();
@@ -52,68 +87,233 @@ pub async fn listen(args: Args) -> Result<()> {
             }
         }
 
-        let mark = args.mark;
-        let ipv4_fwd = args.ipv4_fwd;
-        let ipv6_fwd = args.ipv6_fwd;
-
+        let guard = shutdown::Connection::new();
         tokio::spawn(async move {
-            if let Err(err) = tcp_handle_connection(conn, addr, mark, ipv4_fwd, ipv6_fwd).await {
+            let _guard = guard;
+            if let Err(err) = tcp_handle_connection(&args, conn, addr).await {
                 log::error!("{err:#}");
             }
         });
     }
 }
 
-async fn tcp_handle_connection(
-    mut src: TcpStream,
-    addr: SocketAddr,
-    mark: u32,
-    ipv4_fwd: SocketAddr,
-    ipv6_fwd: SocketAddr,
-) -> Result<()> {
+async fn tcp_handle_connection(args: &Args, mut src: TcpStream, addr: SocketAddr) -> Result<()> {
     src.set_nodelay(true)
         .wrap_err_with(|| format!("failed to set nodelay on {addr} socket"))?;
 
-    let mut buffer = [0u8; u16::MAX as usize];
-    let read_bytes = src
-        .read(&mut buffer)
-        .await
-        .wrap_err_with(|| format!("failed to read the initial proxy-protocol header on {addr}"))?;
+    let mut buffer = [0u8; header::MAX_HEADER_LEN];
+    let read = read_proxy_protocol_header(&mut src, &mut buffer, args.header_policy);
+    let read_bytes = match args.header_timeout {
+        Some(deadline) => match tokio::time::timeout(deadline, read).await {
+            Ok(ret) => ret,
+            Err(_) => {
+                metrics::HEADER_TIMEOUTS.inc();
+                Err(eyre!("timed out after {deadline:?}"))
+            }
+        },
+        None => read.await,
+    }
+    .wrap_err_with(|| format!("failed to read the initial proxy-protocol header on {addr}"))?;
+
+    let header = util::parse_proxy_protocol_header_with_policy(
+        &buffer[..read_bytes],
+        args.header_policy,
+        args.header_versions,
+        args.crc32c,
+    )
+    .wrap_err("failed to parse the proxy protocol header")?;
+    let rest = header.rest;
 
-    let (addr_pair, mut rest, _version) = util::parse_proxy_protocol_header(&buffer[..read_bytes])
-        .wrap_err("failed to parse the proxy protocol header")?;
+    if header.command == Command::Local && args.local_action != LocalAction::Proxy {
+        return tcp_handle_local(args, src, addr, rest).await;
+    }
 
-    let src_addr = match addr_pair {
-        Some((src, _dst)) => src,
-        None => {
+    let local_addr = src
+        .local_addr()
+        .wrap_err("failed to get the local address")?;
+    let (src_addr, dst_addr) = match header.addresses {
+        Addresses::Inet { src, dst } => (src, dst),
+        _ => {
             log::debug!("unknown source, using the downstream connection address");
-            addr
+            (addr, local_addr)
         }
     };
-    let target_addr = match src_addr {
-        SocketAddr::V4(_) => ipv4_fwd,
-        SocketAddr::V6(_) => ipv6_fwd,
+    // routing and the transparent bind need the real family of the client
+    let (src_addr, dst_addr) = (
+        util::normalize_addr(src_addr),
+        util::normalize_addr(dst_addr),
+    );
+    log::info!(
+        "[new conn] [origin: {addr}] [src: {src_addr}]{}",
+        header.tlvs
+    );
+
+    // the ClientHello is read into a buffer of its own, which replaces `rest`
+    let mut peeked = Vec::new();
+    let (rest, hello) = if args.peeks_hello(&dst_addr) {
+        peeked.extend_from_slice(rest);
+        let hello = peek_client_hello(&mut src, &mut peeked, args.hello_timeout).await;
+        (&peeked[..], hello)
+    } else {
+        (rest, None)
+    };
+
+    let connect = move |upstream, transparent| async move {
+        match upstream {
+            Upstream::Inet(target_addr) if transparent => {
+                util::tcp_create_upstream_conn(src_addr, target_addr, args.mark)
+                    .await
+                    .map(UpstreamStream::Tcp)
+            }
+            Upstream::Inet(target_addr) => util::tcp_create_local_conn(target_addr)
+                .await
+                .map(UpstreamStream::Tcp),
+            Upstream::Unix(path) => UnixStream::connect(&path)
+                .await
+                .map(UpstreamStream::Unix)
+                .wrap_err_with(|| format!("failed to connect to unix:{}", path.display())),
+        }
     };
-    log::info!("[new conn] [origin: {addr}] [src: {src_addr}]");
+    let (target, dst) =
+        listener::connect_upstream(args, &src_addr, &dst_addr, hello.as_ref(), connect).await?;
+
+    let proxy_header = match target.reemit() {
+        Some(send_proxy) => {
+            // unix addresses can only be passed on as they came, anything else
+            // is sent as the addresses the connection was picked by
+            let addresses = match header.addresses {
+                Addresses::Unix { .. } => header.addresses,
+                _ => Addresses::Inet {
+                    src: src_addr,
+                    dst: dst_addr,
+                },
+            };
+            util::encode_proxy_header(send_proxy, Transport::Stream, &addresses, header.raw_tlvs)
+        }
+        None => Vec::new(),
+    };
+
+    match dst {
+        UpstreamStream::Tcp(dst) => tcp_forward_with_header(&src, dst, &proxy_header, rest).await,
+        UpstreamStream::Unix(dst) => tcp_forward_with_header(&src, dst, &proxy_header, rest).await,
+    }
+}
+
+enum UpstreamStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+// answers a LOCAL connection, usually a load balancer health check, without
+// touching the transparent upstreams
+async fn tcp_handle_local(
+    args: &Args,
+    mut src: TcpStream,
+    addr: SocketAddr,
+    rest: &[u8],
+) -> Result<()> {
+    match (args.local_action, args.health_upstream) {
+        (LocalAction::Forward, Some(target)) => {
+            log::debug!("[local conn] [origin: {addr}] forwarding to {target}");
+
+            let connect = TcpStream::connect(target);
+            let dst = match args.connect_timeout {
+                Some(deadline) => match tokio::time::timeout(deadline, connect).await {
+                    Ok(ret) => ret.map_err(Into::into),
+                    Err(_) => Err(eyre!("timed out after {deadline:?}")),
+                },
+                None => connect.await.map_err(Into::into),
+            }
+            .wrap_err("failed to connect to the health upstream")?;
+
+            return tcp_forward(&src, dst, rest).await;
+        }
+        (LocalAction::Banner, _) => src
+            .write_all(&args.local_banner)
+            .await
+            .wrap_err("failed to send the banner")?,
+        _ => {}
+    }
+
+    log::debug!("[local conn] [origin: {addr}] answered locally");
+    src.shutdown()
+        .await
+        .wrap_err("failed to close the local connection")
+}
+
+// the readiness API that tokio's TcpStream and UnixStream have in common,
+// which is all that splicing needs
+trait Splice: AsRawFd {
+    async fn readable(&self) -> io::Result<()>;
+    async fn writable(&self) -> io::Result<()>;
+    fn try_io<R>(&self, interest: Interest, f: impl FnOnce() -> io::Result<R>) -> io::Result<R>;
+}
+
+macro_rules! impl_splice {
+    ($($stream:ty),*) => {
+        $(impl Splice for $stream {
+            async fn readable(&self) -> io::Result<()> {
+                <$stream>::readable(self).await
+            }
+
+            async fn writable(&self) -> io::Result<()> {
+                <$stream>::writable(self).await
+            }
+
+            fn try_io<R>(
+                &self,
+                interest: Interest,
+                f: impl FnOnce() -> io::Result<R>,
+            ) -> io::Result<R> {
+                <$stream>::try_io(self, interest, f)
+            }
+        })*
+    };
+}
+
+impl_splice!(TcpStream, UnixStream);
 
-    let mut dst = util::tcp_create_upstream_conn(src_addr, target_addr, mark).await?;
-    tokio::io::copy_buf(&mut rest, &mut dst)
+// replays what was read past the header, then proxies both directions
+async fn tcp_forward<D>(src: &TcpStream, mut dst: D, rest: &[u8]) -> Result<()>
+where
+    D: Splice + AsyncWrite + Unpin,
+{
+    dst.write_all(rest)
         .await
         .wrap_err("failed to re-transmit rest of the initial tcp packet")?;
 
-    let (mut sr, mut sw) = src.split();
-    let (mut dr, mut dw) = dst.split();
+    tcp_splice(src, &dst).await
+}
+
+// sends the re-emitted PROXY header, if any, before anything the client sent
+async fn tcp_forward_with_header<D>(
+    src: &TcpStream,
+    mut dst: D,
+    header: &[u8],
+    rest: &[u8],
+) -> Result<()>
+where
+    D: Splice + AsyncWrite + Unpin,
+{
+    dst.write_all(header)
+        .await
+        .wrap_err("failed to send the proxy protocol header upstream")?;
+
+    tcp_forward(src, dst, rest).await
+}
 
+// proxies both directions until each side has shut down its writer
+async fn tcp_splice(src: &TcpStream, dst: &impl Splice) -> Result<()> {
     let src_to_dst = async {
-        splice_copy(&mut sr, &mut dw).await?;
-        dw.shutdown()
-            .await
+        splice_copy(src, dst).await?;
+        SockRef::from(dst)
+            .shutdown(Shutdown::Write)
             .wrap_err("failed to shutdown the dst writer")
     };
     let dst_to_src = async {
-        splice_copy(&mut dr, &mut sw).await?;
-        sw.shutdown()
-            .await
+        splice_copy(dst, src).await?;
+        SockRef::from(src)
+            .shutdown(Shutdown::Write)
             .wrap_err("failed to shutdown the src writer")
     };
 
@@ -122,11 +322,97 @@ async fn tcp_handle_connection(
         .map(|_| ())
 }
 
+// keeps reading until a complete header is buffered, since it may arrive split
+// across several segments; returns the total number of bytes read, which can
+// include the payload that followed the header
+//
+// unless the header is required, this only reads until it can tell whether the
+// client sent a header at all, so clients that connect directly have to speak first
+async fn read_proxy_protocol_header<S: AsyncRead + Unpin>(
+    src: &mut S,
+    buffer: &mut [u8],
+    policy: HeaderPolicy,
+) -> Result<usize> {
+    let mut read_bytes = 0;
+
+    loop {
+        let data = &buffer[..read_bytes];
+        let has_signature = match policy {
+            HeaderPolicy::Required => Some(true),
+            _ => header::has_signature(data),
+        };
+
+        match has_signature {
+            Some(true) if policy == HeaderPolicy::Forbidden => return Ok(read_bytes),
+            Some(true) => match header::parse(data) {
+                ParseResult::Complete { .. } => return Ok(read_bytes),
+                ParseResult::Invalid(why) => return Err(eyre!(why)),
+                ParseResult::Incomplete => {}
+            },
+            Some(false) => return Ok(read_bytes),
+            _ => {}
+        }
+
+        match src.read(&mut buffer[read_bytes..]).await? {
+            0 => return Err(eyre!("connection closed before the header was complete")),
+            n => read_bytes += n,
+        }
+    }
+}
+
+// reads on past the header until `buffer` holds a complete ClientHello; the
+// connection is routed without one when the client doesn't speak TLS, or
+// doesn't send it within `deadline`
+async fn peek_client_hello(
+    src: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+    deadline: Duration,
+) -> Option<ClientHello> {
+    let read = async {
+        loop {
+            match tls::peek(buffer) {
+                Peek::Hello(hello) => return Ok(Some(hello)),
+                Peek::NotTls => return Ok(None),
+                Peek::Incomplete if buffer.len() >= tls::MAX_HELLO_LEN => return Ok(None),
+                Peek::Incomplete => {}
+            }
+
+            buffer.reserve(4096);
+            if src.read_buf(buffer).await? == 0 {
+                return Ok(None);
+            }
+        }
+    };
+
+    let ret: io::Result<_> = match tokio::time::timeout(deadline, read).await {
+        Ok(ret) => ret,
+        Err(_) => {
+            log::debug!("no ClientHello after {deadline:?}");
+            return None;
+        }
+    };
+    match ret {
+        Ok(Some(hello)) => {
+            log::debug!(
+                "[client hello] [sni: {}] [alpn: {}]",
+                hello.sni.as_deref().unwrap_or("-"),
+                hello.alpn.join(",")
+            );
+            Some(hello)
+        }
+        Ok(None) => None,
+        Err(why) => {
+            log::debug!("failed to peek at the ClientHello: {why}");
+            None
+        }
+    }
+}
+
 // wait for src to be readable
 // splice from src to the pipe buffer
 // wait for dst to be writable
 // splice to dst from the pipe buffer
-async fn splice_copy(src: &mut ReadHalf<'_>, dst: &mut WriteHalf<'_>) -> Result<()> {
+async fn splice_copy(src: &impl Splice, dst: &impl Splice) -> Result<()> {
     use std::io::{Error, ErrorKind::WouldBlock};
 
     let pipe = Pipe::new().wrap_err("failed to create pipe")?;
@@ -134,8 +420,6 @@ async fn splice_copy(src: &mut ReadHalf<'_>, dst: &mut WriteHalf<'_>) -> Result<
     let mut size = 0;
     let mut done = false;
 
-    let src = src.as_ref();
-    let dst = dst.as_ref();
     let src_fd = src.as_raw_fd();
     let dst_fd = dst.as_raw_fd();
 
@@ -147,7 +431,7 @@ async fn splice_copy(src: &mut ReadHalf<'_>, dst: &mut WriteHalf<'_>) -> Result<
             while size < PIPE_BUF_SIZE {
                 match splice(src_fd, pipe.w, PIPE_BUF_SIZE - size) {
                     r if r > 0 => size += r as usize,
-                    r if r == 0 => {
+                    0 => {
                         done = true;
                         break;
                     }
@@ -193,3 +477,117 @@ async fn splice_copy(src: &mut ReadHalf<'_>, dst: &mut WriteHalf<'_>) -> Result<
         Err(Error::last_os_error().into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::ReadBuf;
+
+    // hands out one byte per read, like a client that sends every byte of its
+    // header in its own segment
+    struct Trickle(Vec<u8>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if !self.0.is_empty() {
+                buf.put_slice(&[self.0.remove(0)]);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    async fn read(data: &[u8], policy: HeaderPolicy) -> (Result<usize>, Trickle, Vec<u8>) {
+        let mut src = Trickle(data.to_vec());
+        let mut buffer = vec![0u8; header::MAX_HEADER_LEN];
+        let ret = read_proxy_protocol_header(&mut src, &mut buffer, policy).await;
+        (ret, src, buffer)
+    }
+
+    // a TCP4 header from 192.0.2.1:5555 to 198.51.100.1:443 with an authority
+    // and an ALPN TLV
+    fn v2_with_tlvs() -> Vec<u8> {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x1f".to_vec();
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0x15, 0xb3, 0x01, 0xbb]);
+        header.extend_from_slice(b"\x02\x00\x0bexample.com");
+        header.extend_from_slice(b"\x01\x00\x02h2");
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_a_v1_header_byte_by_byte() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 5555 443\r\n";
+        let (ret, src, buffer) =
+            read(&[&header[..], b"payload"].concat(), HeaderPolicy::Required).await;
+
+        assert_eq!(ret.unwrap(), header.len());
+        assert_eq!(&buffer[..header.len()], header);
+        // nothing past the header is read
+        assert_eq!(src.0, b"payload");
+    }
+
+    #[tokio::test]
+    async fn reads_a_v2_header_with_tlvs_byte_by_byte() {
+        let header = v2_with_tlvs();
+        let (ret, src, buffer) =
+            read(&[&header[..], b"payload"].concat(), HeaderPolicy::Required).await;
+        let read = ret.unwrap();
+
+        assert_eq!(read, header.len());
+        match header::parse(&buffer[..read]) {
+            ParseResult::Complete { len, header } => {
+                assert_eq!(len, read);
+                assert_eq!(header.tlvs.len(), 14 + 5);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+        assert_eq!(src.0, b"payload");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_payload_that_arrived_with_the_header() {
+        let header = v2_with_tlvs();
+        let data = [&header[..], b"payload"].concat();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&data).await.unwrap();
+
+        let mut buffer = vec![0u8; header::MAX_HEADER_LEN];
+        let read = read_proxy_protocol_header(&mut server, &mut buffer, HeaderPolicy::Required)
+            .await
+            .unwrap();
+
+        assert_eq!(read, data.len());
+        assert_eq!(&buffer[header.len()..read], b"payload");
+    }
+
+    #[tokio::test]
+    async fn fails_on_the_first_byte_that_isnt_a_header() {
+        let (ret, src, _) = read(b"GET / HTTP/1.1\r\n", HeaderPolicy::Required).await;
+
+        assert!(ret.is_err());
+        assert_eq!(src.0, b"ET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_client_closes_mid_header() {
+        let (ret, _, _) = read(b"PROXY TCP4 192.0.2.1", HeaderPolicy::Required).await;
+
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_byte_without_a_signature_when_optional() {
+        let (ret, src, _) = read(b"hello", HeaderPolicy::Optional).await;
+
+        assert_eq!(ret.unwrap(), 1);
+        assert_eq!(src.0, b"ello");
+    }
+}
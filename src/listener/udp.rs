@@ -1,9 +1,17 @@
 use simple_eyre::eyre::{eyre, Result, WrapErr};
 
-use crate::{args::Args, util};
-use socket2::SockRef;
+use crate::{
+    args::{Args, SharedArgs},
+    header::{Addresses, Command, Transport},
+    listener,
+    pool::Lease,
+    shutdown,
+    util::{self, LocalAction, Upstream},
+};
+use socket2::{Domain, Socket, Type};
 use std::{
     collections::HashMap,
+    io,
     net::SocketAddr,
     sync::{
         atomic::{AtomicU64, Ordering},
@@ -11,61 +19,176 @@ use std::{
     },
     time::Duration,
 };
-use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
+use tokio::{
+    net::{UdpSocket, UnixDatagram},
+    sync::mpsc,
+    task::JoinHandle,
+};
 
 const MAX_DGRAM_SIZE: usize = 65_507;
-type ConnectionsHashMap = HashMap<SocketAddr, (Arc<UdpProxyConn>, JoinHandle<()>)>;
+// the datagrams a session holds while its upstream is set up or busy
+const SESSION_QUEUE: usize = 64;
+// a session is counted as open for as long as it's in the map
+type ConnectionsHashMap =
+    HashMap<SessionKey, (mpsc::Sender<Vec<u8>>, JoinHandle<()>, shutdown::Session)>;
+// the client address and the kind of session
+type SessionKey = (SocketAddr, SessionKind);
+
+// the LOCAL datagrams that are forwarded to --health-upstream get sessions of
+// their own, so they never mix with the proxied ones of the same address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SessionKind {
+    Proxied,
+    Health,
+}
+
+#[derive(Debug)]
+enum UpstreamSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl UpstreamSocket {
+    async fn send(&self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(sock) => sock.send(buffer).await,
+            Self::Unix(sock) => sock.send(buffer).await,
+        }
+    }
+
+    async fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(sock) => sock.recv(buffer).await,
+            Self::Unix(sock) => sock.recv(buffer).await,
+        }
+    }
+}
+
+// the parts of the header of its first datagram that a session is set up
+// from, which outlive the datagram
+#[derive(Debug)]
+struct Origin {
+    src_addr: SocketAddr,
+    dst_addr: SocketAddr,
+    // the unix addresses of the client, if it sent those
+    unix: Option<(Vec<u8>, Vec<u8>)>,
+    tlvs: Vec<u8>,
+}
 
 #[derive(Debug)]
 struct UdpProxyConn {
-    pub sock: UdpSocket,
+    pub sock: UpstreamSocket,
+    // prefixed to every datagram of a non-transparent upstream, since the
+    // client address can't be passed on with IP_TRANSPARENT
+    pub header: Vec<u8>,
     pub last_activity: AtomicU64,
+    // counts the session against its backend until it's closed
+    _lease: Option<Lease>,
 }
 
 impl UdpProxyConn {
-    fn new(sock: UdpSocket) -> Self {
+    fn new(sock: UpstreamSocket, header: Vec<u8>, lease: Option<Lease>) -> Self {
         Self {
             sock,
+            header,
             last_activity: AtomicU64::new(0),
+            _lease: lease,
+        }
+    }
+
+    // returns the number of payload bytes sent
+    async fn send(&self, buffer: &[u8]) -> io::Result<usize> {
+        if self.header.is_empty() {
+            return self.sock.send(buffer).await;
         }
+
+        let datagram = [&self.header[..], buffer].concat();
+        let sent = self.sock.send(&datagram).await?;
+        Ok(sent.saturating_sub(self.header.len()))
     }
 }
 
-pub async fn listen(args: Args) -> Result<()> {
-    let socket = {
-        let socket = UdpSocket::bind(args.listen_addr)
-            .await
-            .wrap_err_with(|| format!("failed to bind to {}", args.listen_addr))?;
+// listens on the `activated` sockets, if any, or else binds its own
+pub async fn listen(shared: SharedArgs, activated: Vec<Socket>) -> Result<()> {
+    let args = shared.load_full();
+    let sockets = listener::sockets(&args, activated, bind)?;
 
-        let sock_ref = SockRef::from(&socket);
-        sock_ref
-            .set_reuse_port(args.listeners > 1)
-            .wrap_err("failed to set reuse port on listener socket")?;
+    log::info!("listening on: {}", args.listen_addr);
+    listener::serve_sockets(shared, sockets, recv_loop).await
+}
 
-        Arc::new(socket)
-    };
+// SO_REUSEPORT only applies to sockets that set it before they're bound
+fn bind(args: &Args) -> Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(args.listen_addr), Type::DGRAM, None)
+        .wrap_err("failed to create socket")?;
+
+    socket
+        .set_reuse_port(args.listeners > 1)
+        .wrap_err("failed to set reuse port on listener socket")?;
+    socket
+        .set_nonblocking(true)
+        .wrap_err("failed to set nonblocking on listener socket")?;
+    socket
+        .bind(&args.listen_addr.into())
+        .wrap_err_with(|| format!("failed to bind to {}", args.listen_addr))?;
+
+    Ok(socket.into())
+}
+
+// every socket keeps the sessions of its own clients, the kernel sends all the
+// datagrams of a client to the same socket
+//
+// once stopped by a hot upgrade, the datagrams are left to the new process and
+// the sessions only pass on the replies of their upstreams, until the last one
+// has gone idle; SIGTERM and SIGINT close the sessions right away
+async fn recv_loop(shared: SharedArgs, socket: std::net::UdpSocket) -> Result<()> {
+    let socket =
+        Arc::new(UdpSocket::from_std(socket).wrap_err("failed to register the listener socket")?);
 
     let mut buffer = [0u8; MAX_DGRAM_SIZE];
     let mut connections = ConnectionsHashMap::new();
-    let (tx, mut rx) = mpsc::channel::<SocketAddr>(128);
+    let (tx, mut rx) = mpsc::channel::<SessionKey>(128);
+    let mut stopped = false;
 
-    log::info!("listening on: {}", args.listen_addr);
     loop {
         tokio::select! {
-            // close inactive connections in this branch
-            addr = rx.recv() => {
-                if let Some(addr) = addr {
-                    if let Some((_conn, handle)) = connections.remove(&addr) {
-                        log::info!("closing {addr} due to inactivity");
+            // forget the closed sessions in this branch
+            key = rx.recv() => {
+                if let Some(key) = key {
+                    if let Some((_queue, handle, _session)) = connections.remove(&key) {
                         handle.abort();
                     }
                 }
+                if stopped && connections.is_empty() {
+                    return Ok(());
+                }
+            }
+            _ = shutdown::terminated() => {
+                if !connections.is_empty() {
+                    log::info!("closing {} UDP session(s)", connections.len());
+                    shutdown::closed_sessions(connections.len());
+                }
+                for (_, (_queue, handle, _session)) in connections.drain() {
+                    handle.abort();
+                }
+                return Ok(());
+            }
+            _ = shutdown::stopped(), if !stopped => {
+                if connections.is_empty() {
+                    return Ok(());
+                }
+                stopped = true;
             }
             // handle incoming DGRAM packets in this branch
-            ret = socket.recv_from(&mut buffer) => {
+            ret = socket.recv_from(&mut buffer), if !stopped => {
                 let (read, addr) = ret.wrap_err("failed to accept connection")?;
+                let args = shared.load_full();
 
-                if let Some(ref allowed_subnets) = args.allowed_subnets {
+                // a reload only applies to the sessions started after it
+                let known = [SessionKind::Proxied, SessionKind::Health]
+                    .iter()
+                    .any(|&kind| connections.contains_key(&(addr, kind)));
+                if let (Some(allowed_subnets), false) = (&args.allowed_subnets, known) {
                     let ip_addr = addr.ip();
 
                     if !util::check_origin_allowed(&ip_addr, allowed_subnets) {
@@ -92,81 +215,218 @@ pub async fn listen(args: Args) -> Result<()> {
 }
 
 async fn udp_handle_connection(
-    args: &Args,
+    args: &Arc<Args>,
     src: Arc<UdpSocket>,
     addr: SocketAddr,
     buffer: &mut [u8],
     connections: &mut ConnectionsHashMap,
-    tx: mpsc::Sender<SocketAddr>,
+    tx: mpsc::Sender<SessionKey>,
 ) -> Result<()> {
-    let (src_addr, rest, version) = match util::parse_proxy_protocol_header(buffer) {
-        Ok((addr_pair, rest, version)) => match addr_pair {
-            Some((src, _)) => (src, rest, version),
-            None => (addr, rest, version),
-        },
-        Err(err) => return Err(err).wrap_err("failed to parse proxy protocol header"),
-    };
+    let header = util::parse_proxy_protocol_header_with_policy(
+        buffer,
+        args.header_policy,
+        args.header_versions,
+        args.crc32c,
+    )
+    .wrap_err("failed to parse proxy protocol header")?;
+    // routing and the transparent bind need the real family of the client
+    let src_addr = util::normalize_addr(match header.addresses {
+        Addresses::Inet { src, .. } => src,
+        _ => addr,
+    });
+    let rest = header.rest;
 
-    if version < 2 {
+    if header.version == 1 {
         return Err(eyre!(
             "proxy protocol version 1 doesn't support UDP connections"
         ));
     }
-    let target_addr = match src_addr {
-        SocketAddr::V4(_) => args.ipv4_fwd,
-        SocketAddr::V6(_) => args.ipv6_fwd,
+    let mut kind = SessionKind::Proxied;
+    if header.command == Command::Local {
+        match args.local_action {
+            LocalAction::Close => {
+                log::debug!("[local dgram] [origin: {addr}] dropped");
+                return Ok(());
+            }
+            LocalAction::Banner => {
+                src.send_to(&args.local_banner, addr)
+                    .await
+                    .wrap_err("failed to send the banner")?;
+                return Ok(());
+            }
+            LocalAction::Forward => kind = SessionKind::Health,
+            LocalAction::Proxy => {}
+        }
+    }
+    let key = (addr, kind);
+
+    if let Some((queue, _handle, _session)) = connections.get(&key) {
+        udp_queue(queue, addr, rest);
+        return Ok(());
+    }
+
+    // first time connecting
+    if src_addr == addr {
+        log::debug!("unknown source, using the downstream connection address");
+    }
+    log::info!(
+        "[new conn] [origin: {addr}] [src: {src_addr}]{}",
+        header.tlvs
+    );
+
+    let local_addr = src
+        .local_addr()
+        .wrap_err("failed to get the local address")?;
+    let origin = Origin {
+        src_addr,
+        dst_addr: util::normalize_addr(match header.addresses {
+            Addresses::Inet { dst, .. } => dst,
+            _ => local_addr,
+        }),
+        unix: match header.addresses {
+            Addresses::Unix { src, dst } => Some((src.to_vec(), dst.to_vec())),
+            _ => None,
+        },
+        tlvs: header.raw_tlvs.to_vec(),
+    };
+
+    let (queue, datagrams) = mpsc::channel(SESSION_QUEUE);
+    udp_queue(&queue, addr, rest);
+    let handle = tokio::spawn(udp_session(args.clone(), key, origin, src, datagrams, tx));
+    connections.insert(key, (queue, handle, shutdown::Session::new()));
+    Ok(())
+}
+
+// a datagram that comes while its session is still being set up, or faster
+// than the upstream takes them, is dropped once the queue is full, like the
+// network would
+fn udp_queue(queue: &mpsc::Sender<Vec<u8>>, addr: SocketAddr, datagram: &[u8]) {
+    if queue.try_send(datagram.to_vec()).is_err() {
+        log::debug!("[origin: {addr}] dropped a datagram, the session is busy");
+    }
+}
+
+// connecting to the upstream can take --retries attempts of up to
+// --connect-timeout each, so it's done here rather than in the receive loop,
+// which keeps passing on the datagrams of the other clients meanwhile
+//
+// once the session ends, for whatever reason, the receive loop forgets it
+// and the next datagram of the client starts a new one
+async fn udp_session(
+    args: Arc<Args>,
+    key: SessionKey,
+    origin: Origin,
+    src: Arc<UdpSocket>,
+    datagrams: mpsc::Receiver<Vec<u8>>,
+    tx: mpsc::Sender<SessionKey>,
+) {
+    if let Err(why) = udp_run_session(&args, key, &origin, &src, datagrams).await {
+        log::error!("{why:#}");
+    }
+
+    if let Err(why) = tx.send(key).await {
+        log::error!("couldn't send the close command to conn channel: {why}");
+    }
+}
+
+async fn udp_run_session(
+    args: &Args,
+    (addr, kind): SessionKey,
+    origin: &Origin,
+    src: &UdpSocket,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+) -> Result<()> {
+    let dst = match kind {
+        SessionKind::Health => udp_connect_health(args).await?,
+        SessionKind::Proxied => udp_connect_upstream(args, origin).await?,
     };
 
-    let dst = match connections.get(&addr) {
-        Some((dst, _handle)) => {
-            dst.last_activity.fetch_add(1, Ordering::SeqCst);
-            dst.clone()
+    tokio::select! {
+        ret = udp_dst_to_src(addr, origin.src_addr, src, &dst) => ret,
+        _ = udp_src_to_dst(addr, origin.src_addr, &mut datagrams, &dst) => Ok(()),
+        _ = udp_close_after_inactivity(args.close_after, &dst) => {
+            log::info!("closing {addr} due to inactivity");
+            Ok(())
         }
-        // first time connecting
-        None => {
-            if src_addr == addr {
-                log::debug!("unknown source, using the downstream connection address");
+    }
+}
+
+// opens the socket of a session that LOCAL datagrams are forwarded through
+async fn udp_connect_health(args: &Args) -> Result<UdpProxyConn> {
+    let target = args
+        .health_upstream
+        .ok_or_else(|| eyre!("--local-command forward requires --health-upstream"))?;
+    let sock = util::udp_create_local_conn(target).await?;
+
+    Ok(UdpProxyConn::new(
+        UpstreamSocket::Udp(sock),
+        Vec::new(),
+        None,
+    ))
+}
+
+// opens the upstream socket of a new session
+async fn udp_connect_upstream(args: &Args, origin: &Origin) -> Result<UdpProxyConn> {
+    let src_addr = origin.src_addr;
+    let dst_addr = origin.dst_addr;
+
+    let connect = move |upstream, transparent| async move {
+        match upstream {
+            Upstream::Inet(target_addr) if transparent => {
+                util::udp_create_upstream_conn(src_addr, target_addr, args.mark)
+                    .await
+                    .map(UpstreamSocket::Udp)
             }
-            log::info!("[new conn] [origin: {addr}] [src: {src_addr}]");
+            Upstream::Inet(target_addr) => util::udp_create_local_conn(target_addr)
+                .await
+                .map(UpstreamSocket::Udp),
+            Upstream::Unix(path) => util::udp_create_unix_conn(&path).map(UpstreamSocket::Unix),
+        }
+    };
+    let (target, sock) =
+        listener::connect_upstream(args, &src_addr, &dst_addr, None, connect).await?;
 
-            let dst = {
-                let sock = util::udp_create_upstream_conn(src_addr, target_addr, args.mark).await?;
-                Arc::new(UdpProxyConn::new(sock))
+    let proxy_header = match target.reemit() {
+        Some(send_proxy) => {
+            // unix addresses can only be passed on as they came, anything else
+            // is sent as the addresses the session was picked by
+            let addresses = match &origin.unix {
+                Some((src, dst)) => Addresses::Unix { src, dst },
+                None => Addresses::Inet {
+                    src: src_addr,
+                    dst: dst_addr,
+                },
             };
-
-            let src_clone = src.clone();
-            let dst_clone = dst.clone();
-            let handle = tokio::spawn(async move {
-                if let Err(why) = udp_dst_to_src(addr, src_addr, src_clone, dst_clone).await {
-                    log::error!("{why:#}");
-                };
-            });
-            tokio::spawn(udp_close_after_inactivity(
-                addr,
-                args.close_after,
-                tx.clone(),
-                dst.clone(),
-            ));
-
-            connections.insert(addr, (dst.clone(), handle));
-            dst
+            util::encode_proxy_header(send_proxy, Transport::Dgram, &addresses, &origin.tlvs)
         }
+        None => Vec::new(),
     };
 
-    match dst.sock.send(rest).await {
-        Ok(size) => {
-            log::debug!("from [{}] to [{}], size: {}", src_addr, addr, size);
-            Ok(())
+    Ok(UdpProxyConn::new(sock, proxy_header, Some(target.lease)))
+}
+
+// a datagram the upstream refuses is lost, but not the session
+async fn udp_src_to_dst(
+    addr: SocketAddr,
+    src_addr: SocketAddr,
+    datagrams: &mut mpsc::Receiver<Vec<u8>>,
+    dst: &UdpProxyConn,
+) {
+    while let Some(datagram) = datagrams.recv().await {
+        match dst.send(&datagram).await {
+            Ok(size) => log::debug!("from [{}] to [{}], size: {}", src_addr, addr, size),
+            Err(why) => log::error!("failed to write data to the upstream connection: {why}"),
         }
-        Err(err) => Err(err).wrap_err("failed to write data to the upstream connection"),
+
+        dst.last_activity.fetch_add(1, Ordering::SeqCst);
     }
 }
 
 async fn udp_dst_to_src(
     addr: SocketAddr,
     src_addr: SocketAddr,
-    src: Arc<UdpSocket>,
-    dst: Arc<UdpProxyConn>,
+    src: &UdpSocket,
+    dst: &UdpProxyConn,
 ) -> Result<()> {
     let mut buffer = [0u8; MAX_DGRAM_SIZE];
 
@@ -182,12 +442,7 @@ async fn udp_dst_to_src(
     }
 }
 
-async fn udp_close_after_inactivity(
-    addr: SocketAddr,
-    close_after: Duration,
-    tx: mpsc::Sender<SocketAddr>,
-    dst: Arc<UdpProxyConn>,
-) {
+async fn udp_close_after_inactivity(close_after: Duration, dst: &UdpProxyConn) {
     let mut last_activity = dst.last_activity.load(Ordering::SeqCst);
     loop {
         tokio::time::sleep(close_after).await;
@@ -196,8 +451,53 @@ async fn udp_close_after_inactivity(
         }
         last_activity = dst.last_activity.load(Ordering::SeqCst);
     }
+}
 
-    if let Err(why) = tx.send(addr).await {
-        log::error!("couldn't send the close command to conn channel: {why}");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header;
+
+    #[tokio::test]
+    async fn sets_up_sessions_outside_the_receive_loop() {
+        // every attempt fails at once, but the retries back off for seconds
+        let args = Arc::new(
+            Args::parse([
+                "-p",
+                "udp",
+                "-4",
+                "unix:/nonexistent/a,unix:/nonexistent/b",
+                "--retries",
+                "2",
+                "--retry-backoff",
+                "10000",
+            ])
+            .unwrap(),
+        );
+        let src = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = "127.0.0.1:5555".parse().unwrap();
+        let mut datagram = header::encode_v2(
+            Command::Proxy,
+            Transport::Dgram,
+            &Addresses::Inet {
+                src: "192.0.2.1:5555".parse().unwrap(),
+                dst: "198.51.100.1:443".parse().unwrap(),
+            },
+            &[],
+        );
+        datagram.extend_from_slice(b"payload");
+
+        let mut connections = ConnectionsHashMap::new();
+        let (tx, _rx) = mpsc::channel(1);
+        let handle = udp_handle_connection(&args, src, addr, &mut datagram, &mut connections, tx);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("the receive loop waited for the upstream")
+            .unwrap();
+
+        assert!(connections.contains_key(&(addr, SessionKind::Proxied)));
+        for (_queue, handle, _session) in connections.into_values() {
+            handle.abort();
+        }
     }
 }
@@ -1,10 +1,26 @@
+mod activation;
 mod args;
+mod config;
+mod header;
+mod health;
 mod listener;
+mod metrics;
 mod pipe;
+mod pool;
+mod reload;
+mod route;
+mod shutdown;
+mod tls;
+mod upgrade;
 mod util;
 
+use arc_swap::ArcSwap;
+use args::SharedArgs;
 use env_logger::{Env, DEFAULT_FILTER_ENV};
 use listener::{tcp, udp};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use util::Protocol;
 
 #[tokio::main]
 async fn main() {
@@ -17,13 +33,116 @@ async fn main() {
             return;
         }
     };
+    let listeners = match args.listeners() {
+        Ok(listeners) => listeners,
+        Err(why) => {
+            log::error!("{why:#}");
+            return;
+        }
+    };
+
+    // the process wide flags are the same for every listener
+    let args = &listeners[0];
+    if let Some(addr) = args.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(why) = metrics::serve(addr).await {
+                log::error!("{why:#}");
+            }
+        });
+    }
+
+    let (resolve_ttl, health_interval, drain_timeout) =
+        (args.resolve_ttl, args.health_interval, args.drain_timeout);
 
-    let ret = match args.protocol {
-        util::Protocol::Tcp => tcp::listen(args).await,
-        util::Protocol::Udp => udp::listen(args).await,
+    // a process started by a hot upgrade listens on the sockets of the old one
+    let (upgrading, mut activated) = match upgrade::inherited() {
+        Ok(Some((conn, sockets))) => (Some(conn), sockets),
+        Ok(None) => match activation::Sockets::from_env() {
+            Ok(activated) => (None, activated),
+            Err(why) => {
+                log::error!("{why:#}");
+                return;
+            }
+        },
+        Err(why) => {
+            log::error!("{why:#}");
+            return;
+        }
     };
 
-    if let Err(why) = ret {
+    // a listener that fails is reported, the others keep running
+    let mut claimed = Vec::new();
+    let mut failed = false;
+    for mut args in listeners {
+        match activated.claim(&mut args) {
+            Ok(sockets) => claimed.push((Arc::new(ArcSwap::from_pointee(args)), sockets)),
+            Err(why) => {
+                log::error!("[{} {}] {why:#}", args.protocol, args.listen_addr);
+                failed = true;
+            }
+        }
+    }
+    activated.close_unclaimed();
+    if claimed.is_empty() {
+        return;
+    }
+
+    let shared: Vec<SharedArgs> = claimed.iter().map(|(args, _)| args.clone()).collect();
+    pool::resolve(args::all_pools(&shared)).await;
+    let pools = shared.clone();
+    pool::keep_resolving(move || args::all_pools(&pools), resolve_ttl);
+    if let Some(interval) = health_interval {
+        health::spawn(shared.clone(), interval);
+    }
+
+    let mut tasks = JoinSet::new();
+    for (args, sockets) in claimed {
+        let (protocol, addr) = {
+            let args = args.load();
+            (args.protocol, args.listen_addr)
+        };
+        tasks.spawn(async move {
+            let ret = match protocol {
+                Protocol::Tcp => tcp::listen(args, sockets).await,
+                Protocol::Udp => udp::listen(args, sockets).await,
+            };
+            (protocol, addr, ret)
+        });
+    }
+
+    // the old process keeps serving if the new one can't take all of it over
+    if let Some(conn) = upgrading {
+        if failed {
+            log::error!("not taking over from the old process");
+            return;
+        }
+        upgrade::took_over(conn);
+    }
+    if let Err(why) = shutdown::watch()
+        .and_then(|_| upgrade::watch())
+        .and_then(|_| reload::watch(shared))
+    {
         log::error!("{why:#}");
     }
+
+    // the UDP listeners keep running until their sessions are drained, which
+    // only takes as long as --drain-timeout
+    loop {
+        let ret = tokio::select! {
+            ret = tasks.join_next() => ret,
+            _ = shutdown::stopped() => break,
+        };
+        match ret {
+            Some(Ok((protocol, addr, Err(why)))) => log::error!("[{protocol} {addr}] {why:#}"),
+            Some(Ok(_)) => {}
+            Some(Err(why)) => log::error!("a listener panicked: {why}"),
+            None => break,
+        }
+    }
+
+    // the listeners only return early once they've been stopped
+    if shutdown::is_stopping() {
+        upgrade::release();
+        shutdown::drain(drain_timeout).await;
+    }
 }
@@ -0,0 +1,212 @@
+use simple_eyre::eyre::{Result, WrapErr};
+
+use crate::{pool::Backend, shutdown};
+use std::{
+    fmt::Write,
+    fs,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+    time::timeout,
+};
+
+// how long a scrape may take to send its request and to read the response, so
+// that idle clients don't hold on to their connections
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Counter {
+    name: &'static str,
+    // e.g. `reason="refused"`, counters that share a name differ in these
+    labels: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Self::labeled(name, "", help)
+    }
+
+    const fn labeled(name: &'static str, labels: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            labels,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub static HEADER_TIMEOUTS: Counter = Counter::new(
+    "mmproxy_header_timeouts_total",
+    "TCP connections closed because the PROXY header didn't arrive in time.",
+);
+
+macro_rules! dial_errors {
+    ($($counter:ident => $reason:literal),*) => {
+        $(pub static $counter: Counter = Counter::labeled(
+            "mmproxy_upstream_dial_errors_total",
+            concat!("reason=\"", $reason, "\""),
+            "Failed upstream connection attempts, by reason.",
+        );)*
+    };
+}
+
+dial_errors!(
+    DIAL_REFUSED => "refused",
+    DIAL_TIMEOUT => "timeout",
+    DIAL_UNREACHABLE => "unreachable",
+    DIAL_ADDR_IN_USE => "addr_in_use",
+    DIAL_PERMISSION_DENIED => "permission_denied",
+    DIAL_OTHER => "other"
+);
+
+// counters that share a name have to be next to each other
+static COUNTERS: &[&Counter] = &[
+    &HEADER_TIMEOUTS,
+    &DIAL_REFUSED,
+    &DIAL_TIMEOUT,
+    &DIAL_UNREACHABLE,
+    &DIAL_ADDR_IN_USE,
+    &DIAL_PERMISSION_DENIED,
+    &DIAL_OTHER,
+];
+
+// the health checked upstreams, one backend each
+static UPSTREAMS: Mutex<Vec<Arc<Backend>>> = Mutex::new(Vec::new());
+
+pub fn export_upstreams(backends: impl IntoIterator<Item = Arc<Backend>>) {
+    let mut backends: Vec<_> = backends.into_iter().collect();
+    backends.sort_by_key(|backend| backend.upstream.to_string());
+
+    *UPSTREAMS.lock().unwrap() = backends;
+}
+
+// renders every metric in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let mut last_name = "";
+    for counter in COUNTERS {
+        if counter.name != last_name {
+            let _ = writeln!(out, "# HELP {} {}", counter.name, counter.help);
+            let _ = writeln!(out, "# TYPE {} counter", counter.name);
+            last_name = counter.name;
+        }
+
+        let value = counter.value.load(Ordering::Relaxed);
+        let _ = match counter.labels {
+            "" => writeln!(out, "{} {value}", counter.name),
+            labels => writeln!(out, "{}{{{labels}}} {value}", counter.name),
+        };
+    }
+
+    let upstreams = UPSTREAMS.lock().unwrap();
+    if !upstreams.is_empty() {
+        let name = "mmproxy_upstream_up";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Whether the upstream passed its health checks."
+        );
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for backend in upstreams.iter() {
+            // Debug quotes and escapes the label value the way Prometheus expects
+            let upstream = backend.upstream.to_string();
+            let _ = writeln!(
+                out,
+                "{name}{{upstream={upstream:?}}} {}",
+                backend.is_healthy() as u8
+            );
+        }
+    }
+
+    // the kernel drops connections that stay idle past TCP_DEFER_ACCEPT before
+    // we ever see them, so the only place they are counted is /proc
+    if let Some(drops) = netstat_counter("TcpExt:", "TCPDeferAcceptDrop") {
+        let name = "mmproxy_defer_accept_drops_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Idle connections dropped by TCP_DEFER_ACCEPT in this network namespace."
+        );
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {drops}");
+    }
+
+    out
+}
+
+// /proc/net/netstat holds pairs of lines: a header with the field names and
+// a line with their values, both starting with the same prefix
+fn netstat_counter(prefix: &str, field: &str) -> Option<u64> {
+    let contents = fs::read_to_string("/proc/net/netstat").ok()?;
+    let mut lines = contents.lines().filter(|line| line.starts_with(prefix));
+
+    let names = lines.next()?.split_whitespace();
+    let values = lines.next()?.split_whitespace();
+    names
+        .zip(values)
+        .find(|(name, _)| *name == field)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+// a minimal HTTP endpoint for scrapers, every request gets the metrics
+// the process started by a hot upgrade binds the address while this one is
+// still serving, until it stops
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4(),
+        SocketAddr::V6(_) => TcpSocket::new_v6(),
+    }
+    .wrap_err("failed to create the metrics socket")?;
+    socket
+        .set_reuseport(true)
+        .wrap_err("failed to set reuseport on the metrics socket")?;
+    socket
+        .bind(addr)
+        .wrap_err_with(|| format!("failed to bind the metrics listener to {addr}"))?;
+    let listener = socket
+        .listen(128)
+        .wrap_err("failed to start the metrics listener")?;
+
+    log::info!("serving metrics on: {addr}");
+    loop {
+        let (conn, _) = tokio::select! {
+            ret = listener.accept() => ret.wrap_err("failed to accept metrics connection")?,
+            _ = shutdown::stopped() => return Ok(()),
+        };
+
+        tokio::spawn(async move {
+            if let Err(why) = respond(conn).await {
+                log::debug!("failed to serve metrics: {why}");
+            }
+        });
+    }
+}
+
+async fn respond(mut conn: TcpStream) -> std::io::Result<()> {
+    // the request itself doesn't matter, but it has to be drained before closing
+    let mut buffer = [0u8; 1024];
+    let _ = timeout(SCRAPE_TIMEOUT, conn.read(&mut buffer)).await??;
+
+    let body = render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    timeout(SCRAPE_TIMEOUT, async {
+        conn.write_all(response.as_bytes()).await?;
+        conn.shutdown().await
+    })
+    .await?
+}
@@ -0,0 +1,581 @@
+use crate::util::Upstream;
+
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::IpAddr,
+    os::unix::ffi::OsStrExt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+// how a pool spreads connections over its backends, all of them take the
+// weights into account
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    RoundRobin,
+    LeastConn,
+    // the less loaded of two randomly picked backends
+    RandomTwo,
+    // rendezvous hashing on the client address, so a client sticks to its
+    // backend and only moves when that backend goes away
+    Hash,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-conn" => Ok(Self::LeastConn),
+            "random-two" => Ok(Self::RandomTwo),
+            "hash" => Ok(Self::Hash),
+            _ => Err(format!("invalid balance value: {s}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Backend {
+    pub upstream: Upstream,
+    pub weight: u32,
+    // connections and UDP sessions currently going to this backend
+    active: AtomicUsize,
+    // cleared by the health checks, unhealthy backends aren't picked
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    // compares the connections per weight without dividing
+    fn load_cmp(&self, other: &Self) -> CmpOrdering {
+        let load = self.active() as u64 * other.weight as u64;
+        let other_load = other.active() as u64 * self.weight as u64;
+        load.cmp(&other_load)
+    }
+}
+
+impl Backend {
+    fn new(upstream: Upstream, weight: u32) -> Self {
+        Self {
+            upstream,
+            weight,
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+        }
+    }
+}
+
+// an upstream given as "host:port", which is resolved into a backend for
+// every address of the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Name {
+    pub host: String,
+    pub weight: u32,
+}
+
+// one upstream of a pool as it was configured
+#[derive(Debug)]
+pub enum Entry {
+    Backend(Backend),
+    Name(Name),
+}
+
+// "<upstream>" or "<upstream>*<weight>", where the upstream can also be a
+// "host:port" name
+impl FromStr for Entry {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (upstream, weight) = match s.rsplit_once('*') {
+            Some((upstream, weight)) => match weight.parse() {
+                Ok(weight) if weight > 0 => (upstream, weight),
+                _ => return Err(format!("invalid weight: {weight}")),
+            },
+            None => (s, 1),
+        };
+
+        if let Ok(upstream) = upstream.parse() {
+            return Ok(Self::Backend(Backend::new(upstream, weight)));
+        }
+        match upstream.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Self::Name(Name {
+                    host: upstream.to_owned(),
+                    weight,
+                }))
+            }
+            _ => Err(format!("invalid upstream: {upstream}")),
+        }
+    }
+}
+
+// counts a connection against its backend for as long as it's alive
+#[derive(Debug)]
+pub struct Lease(Arc<Backend>);
+
+impl Lease {
+    fn new(backend: Arc<Backend>) -> Self {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Self(backend)
+    }
+
+    pub fn upstream(&self) -> &Upstream {
+        &self.0.upstream
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// clones share the backends and their connection counts
+#[derive(Debug, Clone)]
+pub struct Pool {
+    pub policy: Policy,
+    fixed: Vec<Arc<Backend>>,
+    names: Arc<[Name]>,
+    // the backends of every name, in the same order; replaced whenever the
+    // names are resolved again, connections keep the backend they leased
+    resolved: Arc<RwLock<Vec<Vec<Arc<Backend>>>>>,
+    next: Arc<AtomicU64>,
+}
+
+impl Pool {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        let mut fixed = Vec::new();
+        let mut names = Vec::new();
+        for entry in entries {
+            match entry {
+                Entry::Backend(backend) => fixed.push(Arc::new(backend)),
+                Entry::Name(name) => names.push(name),
+            }
+        }
+
+        Self {
+            policy: Policy::default(),
+            fixed,
+            resolved: Arc::new(RwLock::new(vec![Vec::new(); names.len()])),
+            names: names.into(),
+            next: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn backends(&self) -> Vec<Arc<Backend>> {
+        let resolved = self.resolved.read().unwrap();
+        self.fixed
+            .iter()
+            .chain(resolved.iter().flatten())
+            .cloned()
+            .collect()
+    }
+
+    pub fn has_names(&self) -> bool {
+        !self.names.is_empty()
+    }
+
+    // whether both were configured with the same upstreams and policy, so that
+    // a reload can keep the pool it has, along with its state
+    pub fn same_config(&self, other: &Pool) -> bool {
+        let fixed = |pool: &Pool| {
+            pool.fixed
+                .iter()
+                .map(|backend| (backend.upstream.clone(), backend.weight))
+                .collect::<Vec<_>>()
+        };
+        self.policy == other.policy && self.names == other.names && fixed(self) == fixed(other)
+    }
+
+    // whether both are clones of the same pool
+    pub fn ptr_eq(&self, other: &Pool) -> bool {
+        Arc::ptr_eq(&self.next, &other.next)
+    }
+
+    // resolves every name through the system resolver; a name that fails to
+    // resolve keeps the addresses it had
+    pub async fn resolve(&self) {
+        for (i, name) in self.names.iter().enumerate() {
+            let mut addrs: Vec<_> = match tokio::net::lookup_host(&name.host).await {
+                Ok(addrs) => addrs.collect(),
+                Err(why) => {
+                    log::warn!("failed to resolve {}: {why}", name.host);
+                    continue;
+                }
+            };
+            addrs.sort();
+            addrs.dedup();
+            let upstreams: Vec<_> = addrs.iter().copied().map(Upstream::Inet).collect();
+
+            let mut resolved = self.resolved.write().unwrap();
+            let old = &resolved[i];
+            if old.iter().map(|backend| &backend.upstream).eq(&upstreams) {
+                continue;
+            }
+
+            // backends that stay keep their connection counts and health
+            let new = upstreams
+                .into_iter()
+                .map(|upstream| {
+                    old.iter()
+                        .find(|backend| backend.upstream == upstream)
+                        .cloned()
+                        .unwrap_or_else(|| Arc::new(Backend::new(upstream, name.weight)))
+                })
+                .collect();
+            log::info!(
+                "{} resolved to {}",
+                name.host,
+                addrs
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            resolved[i] = new;
+        }
+    }
+
+    // picks one of the healthy backends that `eligible` accepts for a
+    // connection of `client`, the real client address from the PROXY header
+    pub fn pick(&self, client: IpAddr, eligible: impl Fn(&Upstream) -> bool) -> Option<Lease> {
+        let resolved = self.resolved.read().unwrap();
+        let candidates: Vec<_> = self
+            .fixed
+            .iter()
+            .chain(resolved.iter().flatten())
+            .filter(|backend| backend.is_healthy() && eligible(&backend.upstream))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let backend = match self.policy {
+            Policy::RoundRobin => by_weight(&candidates, self.next.fetch_add(1, Ordering::Relaxed)),
+            // ties are broken by a rotating start, otherwise the first
+            // backend would take every connection of an idle pool
+            Policy::LeastConn => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) as usize;
+                candidates
+                    .iter()
+                    .cycle()
+                    .skip(start % candidates.len())
+                    .take(candidates.len())
+                    .copied()
+                    .min_by(|a, b| a.load_cmp(b))
+                    .unwrap_or(candidates[0])
+            }
+            Policy::RandomTwo => {
+                let a = by_weight(&candidates, random());
+                let b = by_weight(&candidates, random());
+                if b.load_cmp(a) == CmpOrdering::Less {
+                    b
+                } else {
+                    a
+                }
+            }
+            Policy::Hash => candidates
+                .iter()
+                .copied()
+                .max_by(|a, b| rendezvous_score(a, client).total_cmp(&rendezvous_score(b, client)))
+                .unwrap_or(candidates[0]),
+        };
+
+        Some(Lease::new(backend.clone()))
+    }
+
+    // prefers the backends that haven't failed a connection yet, but comes back
+    // to the failed ones once there are no others
+    pub fn pick_untried(
+        &self,
+        client: IpAddr,
+        eligible: impl Fn(&Upstream) -> bool,
+        tried: &[Upstream],
+    ) -> Option<Lease> {
+        self.pick(client, |upstream| {
+            eligible(upstream) && !tried.contains(upstream)
+        })
+        .or_else(|| self.pick(client, eligible))
+    }
+}
+
+// the backend that the n-th slot falls on, each backend takes as many slots
+// as its weight
+fn by_weight<'a>(candidates: &[&'a Arc<Backend>], n: u64) -> &'a Arc<Backend> {
+    let total: u64 = candidates.iter().map(|backend| backend.weight as u64).sum();
+    let mut slot = n % total;
+
+    for backend in candidates {
+        match slot.checked_sub(backend.weight as u64) {
+            Some(left) => slot = left,
+            None => return backend,
+        }
+    }
+
+    candidates[0]
+}
+
+// every RandomState is seeded differently, which is all the randomness that
+// picking backends needs
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// weighted rendezvous hashing: the backend with the highest score wins
+fn rendezvous_score(backend: &Backend, client: IpAddr) -> f64 {
+    let client = match client {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    };
+    let hash = match backend.upstream {
+        Upstream::Inet(addr) => {
+            let ip = match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                IpAddr::V6(ip) => ip.octets(),
+            };
+            fnv1a(&[&client, &ip, &addr.port().to_be_bytes()])
+        }
+        Upstream::Unix(ref path) => fnv1a(&[&client, path.as_os_str().as_bytes()]),
+    };
+
+    // a uniform number in (0, 1)
+    let unit = ((mix(hash) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    backend.weight as f64 / -unit.ln()
+}
+
+// FNV-1a, which unlike the std hashers never changes, so clients keep their
+// backend across restarts and upgrades
+fn fnv1a(chunks: &[&[u8]]) -> u64 {
+    chunks
+        .iter()
+        .flat_map(|chunk| chunk.iter())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+// the splitmix64 finalizer, FNV alone spreads similar inputs poorly
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+// a comma separated list of upstreams, e.g. "127.0.0.1:8000*2,backend:8001"
+pub fn parse_pool(list: &str) -> Result<Pool, String> {
+    list.split(',')
+        .map(Entry::from_str)
+        .collect::<Result<_, _>>()
+        .map(Pool::new)
+}
+
+// resolves the names of every pool, a pool that several listeners share is
+// resolved once
+pub async fn resolve(pools: Vec<Pool>) {
+    for pool in unique(pools) {
+        pool.resolve().await;
+    }
+}
+
+// resolves the names again every `ttl` in the background, from the first time
+// that any pool has names on; `pools` is asked for the pools every time, which
+// can change on reload
+pub fn keep_resolving<F>(pools: F, ttl: Duration)
+where
+    F: Fn() -> Vec<Pool> + Send + 'static,
+{
+    static RESOLVING: AtomicBool = AtomicBool::new(false);
+    if unique(pools()).is_empty() || RESOLVING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(ttl).await;
+            resolve(pools()).await;
+        }
+    });
+}
+
+// the pools with names, without the clones of one another
+pub fn unique(pools: Vec<Pool>) -> Vec<Pool> {
+    let mut unique: Vec<Pool> = Vec::new();
+    for pool in pools.into_iter().filter(Pool::has_names) {
+        if !unique.iter().any(|other| other.ptr_eq(&pool)) {
+            unique.push(pool);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(list: &str, policy: Policy) -> Pool {
+        let mut pool = parse_pool(list).unwrap();
+        pool.policy = policy;
+        pool
+    }
+
+    fn client(n: u32) -> IpAddr {
+        IpAddr::from((0x0a00_0000 + n).to_be_bytes())
+    }
+
+    // how many of the picks fell on each backend
+    fn count(pool: &Pool, picks: impl Iterator<Item = Lease>) -> Vec<usize> {
+        let backends = pool.backends();
+        let mut counts = vec![0; backends.len()];
+        for lease in picks {
+            let i = backends.iter().position(|b| Arc::ptr_eq(b, &lease.0));
+            counts[i.unwrap()] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn parses_entries() {
+        let pool = parse_pool("127.0.0.1:1*2,unix:/run/b.sock,backend:8000*3").unwrap();
+        let backends = pool.backends();
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[0].weight, 2);
+        assert_eq!(backends[1].upstream, "unix:/run/b.sock".parse().unwrap());
+        assert_eq!(
+            &pool.names[..],
+            [Name {
+                host: "backend:8000".to_owned(),
+                weight: 3
+            }]
+        );
+
+        for list in ["127.0.0.1:1*0", "127.0.0.1:1*x", "backend", ":80", "a:b"] {
+            assert!(parse_pool(list).is_err(), "{list}");
+        }
+    }
+
+    #[test]
+    fn round_robin_weights() {
+        let pool = pool("127.0.0.1:1,127.0.0.1:2*3", Policy::RoundRobin);
+        let picks = (0..40).map(|_| pool.pick(client(0), |_| true).unwrap());
+        assert_eq!(count(&pool, picks), [10, 30]);
+    }
+
+    #[test]
+    fn skips_unhealthy_and_ineligible() {
+        let pool = pool("127.0.0.1:1,127.0.0.1:2,127.0.0.1:3", Policy::RoundRobin);
+        let backends = pool.backends();
+        backends[0].set_healthy(false);
+        let third: Upstream = "127.0.0.1:3".parse().unwrap();
+
+        let picks = (0..10).map(|_| pool.pick(client(0), |up| *up != third).unwrap());
+        assert_eq!(count(&pool, picks), [0, 10, 0]);
+        assert!(pool
+            .pick(client(0), |up| *up == backends[0].upstream)
+            .is_none());
+
+        // a tried backend is only picked again when there's no other
+        let tried = [backends[1].upstream.clone()];
+        let lease = pool.pick_untried(client(0), |_| true, &tried).unwrap();
+        assert_eq!(*lease.upstream(), third);
+        let lease = pool
+            .pick_untried(client(0), |up| *up != third, &tried)
+            .unwrap();
+        assert_eq!(*lease.upstream(), tried[0]);
+    }
+
+    #[test]
+    fn least_conn_load_per_weight() {
+        let pool = pool("127.0.0.1:1,127.0.0.1:2*2", Policy::LeastConn);
+
+        // the picks are kept, so the counts go up as they're made
+        let leases: Vec<_> = (0..30)
+            .map(|_| pool.pick(client(0), |_| true).unwrap())
+            .collect();
+        let backends = pool.backends();
+        assert_eq!(backends[0].active(), 10);
+        assert_eq!(backends[1].active(), 20);
+
+        drop(leases);
+        assert_eq!(backends[0].active(), 0);
+        assert_eq!(backends[1].active(), 0);
+    }
+
+    #[test]
+    fn least_conn_tie_break() {
+        // every pick is dropped at once, so the backends are always tied
+        let pool = pool("127.0.0.1:1,127.0.0.1:2,127.0.0.1:3", Policy::LeastConn);
+        let picks = (0..30).map(|_| pool.pick(client(0), |_| true).unwrap());
+        assert_eq!(count(&pool, picks), [10, 10, 10]);
+    }
+
+    #[test]
+    fn hash_sticks() {
+        let list = "127.0.0.1:1,127.0.0.1:2,127.0.0.1:3,unix:/run/a.sock";
+        let pool = pool(list, Policy::Hash);
+        let picked: Vec<_> = (0..1000)
+            .map(|n| pool.pick(client(n), |_| true).unwrap().upstream().clone())
+            .collect();
+
+        // the same pick every time, and in a new pool with the same backends
+        let again = self::pool(list, Policy::Hash);
+        for (n, upstream) in picked.iter().enumerate() {
+            let lease = again.pick(client(n as u32), |_| true).unwrap();
+            assert_eq!(lease.upstream(), upstream);
+        }
+
+        // only the clients of a removed backend move
+        let gone: Upstream = "127.0.0.1:2".parse().unwrap();
+        for (n, upstream) in picked.iter().enumerate() {
+            let lease = pool.pick(client(n as u32), |up| *up != gone).unwrap();
+            if *upstream != gone {
+                assert_eq!(lease.upstream(), upstream);
+            }
+        }
+
+        let spread = count(
+            &pool,
+            (0..1000).map(|n| pool.pick(client(n), |_| true).unwrap()),
+        );
+        assert!(
+            spread.iter().all(|&n| (180..=320).contains(&n)),
+            "{spread:?}"
+        );
+    }
+
+    #[test]
+    fn hash_weights() {
+        let pool = pool("127.0.0.1:1,127.0.0.1:2*3", Policy::Hash);
+        let spread = count(
+            &pool,
+            (0..4000).map(|n| pool.pick(client(n), |_| true).unwrap()),
+        );
+        assert!((850..=1150).contains(&spread[0]), "{spread:?}");
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        // the published FNV-1a test vectors
+        assert_eq!(fnv1a(&[]), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(&[b"a"]), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(&[b"foo", b"bar"]), 0x85944171f73967e8);
+    }
+}
@@ -0,0 +1,301 @@
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::{
+    args::{self, Args, SharedArgs},
+    pool,
+};
+use std::{fs, sync::Arc};
+use tokio::signal::unix::{signal, SignalKind};
+
+// a diff longer than this is cut short in the log
+const MAX_DIFF_LINES: usize = 50;
+// changed parts with more lines than this, old times new, aren't matched up
+const MAX_DIFF_CELLS: usize = 1_000_000;
+
+// on SIGHUP, parses the command line and every file it names again, e.g. the
+// config, allowed subnets and routes, and swaps in the new arguments of each
+// listener for the connections that come after; the new arguments are only
+// used if they all parse and define the same listeners, otherwise the old
+// ones stay in force
+pub fn watch(listeners: Vec<SharedArgs>) -> Result<()> {
+    let mut hup = signal(SignalKind::hangup()).wrap_err("failed to listen for SIGHUP")?;
+    let running: Vec<_> = listeners.iter().map(|args| args.load_full()).collect();
+    let mut files = Files::read(&running);
+
+    tokio::spawn(async move {
+        while hup.recv().await.is_some() {
+            log::info!("[reload] SIGHUP received, reloading");
+            match reload(&listeners).await {
+                Ok(running) => {
+                    let next = Files::read(&running);
+                    files.log_changes(&next, log::Level::Info);
+                    files = next;
+                    log::info!("[reload] new connections use the new config");
+                }
+                Err(why) => {
+                    log::error!("[reload] rejected, keeping the running config: {why:#}");
+                    let paths: Vec<_> = files.0.iter().map(|(path, _)| path.clone()).collect();
+                    files.log_changes(&Files::read_paths(paths), log::Level::Error);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// returns the new arguments once every listener uses them
+async fn reload(listeners: &[SharedArgs]) -> Result<Vec<Arc<Args>>> {
+    // argwerk's errors already include their cause
+    let args = args::parse_args().map_err(|why| eyre!("{why}"))?;
+    let mut new = args.listeners()?;
+    let old: Vec<_> = listeners.iter().map(|args| args.load_full()).collect();
+
+    if new.len() != old.len() {
+        return Err(eyre!(
+            "{} listeners instead of {}, listeners are only added or removed by a restart",
+            new.len(),
+            old.len()
+        ));
+    }
+    let mut swaps = Vec::new();
+    for running in &old {
+        let i = new
+            .iter()
+            .position(|args| same_listener(running, args))
+            .ok_or_else(|| {
+                eyre!(
+                    "no {} listener on {}, listeners are only added or removed by a restart",
+                    running.protocol,
+                    running.listen_addr
+                )
+            })?;
+        let mut args = new.swap_remove(i);
+        // a named listener listens wherever its activated sockets are bound
+        args.listen_addr = running.listen_addr;
+
+        let changed = restart_only_changes(running, &args);
+        if !changed.is_empty() {
+            return Err(eyre!(
+                "{} can only be changed by a restart",
+                changed.join(", ")
+            ));
+        }
+        swaps.push(args);
+    }
+
+    // pools that didn't change keep their connection counts, health and
+    // resolved names, the new ones are resolved before they're used
+    for args in &mut swaps {
+        for pool in args.pools_mut() {
+            match old
+                .iter()
+                .flat_map(|args| args.pools())
+                .find(|old| old.same_config(pool))
+            {
+                Some(old) => *pool = old.clone(),
+                None => pool.resolve().await,
+            }
+        }
+    }
+
+    let running = listeners
+        .iter()
+        .zip(swaps)
+        .map(|(listener, args)| {
+            let args = Arc::new(args);
+            listener.store(args.clone());
+            args
+        })
+        .collect();
+
+    // the names may be the first ones
+    let pools = listeners.to_vec();
+    pool::keep_resolving(move || args::all_pools(&pools), old[0].resolve_ttl);
+    Ok(running)
+}
+
+fn same_listener(running: &Args, args: &Args) -> bool {
+    running.protocol == args.protocol
+        && running.fd_name == args.fd_name
+        && (running.fd_name.is_some() || running.listen_addr == args.listen_addr)
+}
+
+// the flags that differ from the running ones, of those that are only read at
+// startup: the process wide ones, and the ones the sockets are set up with
+fn restart_only_changes(running: &Args, args: &Args) -> Vec<&'static str> {
+    [
+        (
+            "--health-interval",
+            running.health_interval != args.health_interval,
+        ),
+        (
+            "--health-timeout",
+            running.health_timeout != args.health_timeout,
+        ),
+        ("--health-rise", running.health_rise != args.health_rise),
+        ("--health-fall", running.health_fall != args.health_fall),
+        ("--health-send", running.health_send != args.health_send),
+        (
+            "--health-expect",
+            running.health_expect != args.health_expect,
+        ),
+        ("--resolve-ttl", running.resolve_ttl != args.resolve_ttl),
+        ("--metrics-addr", running.metrics_addr != args.metrics_addr),
+        (
+            "--drain-timeout",
+            running.drain_timeout != args.drain_timeout,
+        ),
+        ("--listeners", running.listeners != args.listeners),
+        (
+            "--pin-listeners",
+            running.pin_listeners != args.pin_listeners,
+        ),
+        ("--backlog", running.backlog != args.backlog),
+        ("--defer-accept", running.defer_accept != args.defer_accept),
+    ]
+    .into_iter()
+    .filter_map(|(flag, changed)| changed.then_some(flag))
+    .collect()
+}
+
+// the contents of the files that the arguments were read from, unreadable
+// files are empty
+struct Files(Vec<(String, String)>);
+
+impl Files {
+    fn read(listeners: &[Arc<Args>]) -> Self {
+        let mut paths: Vec<String> = Vec::new();
+        for path in listeners.iter().flat_map(|args| &args.files) {
+            if !paths.contains(path) {
+                paths.push(path.clone());
+            }
+        }
+        Self::read_paths(paths)
+    }
+
+    fn read_paths(paths: Vec<String>) -> Self {
+        Self(
+            paths
+                .into_iter()
+                .map(|path| {
+                    let contents = fs::read_to_string(&path).unwrap_or_default();
+                    (path, contents)
+                })
+                .collect(),
+        )
+    }
+
+    // logs how every file of `next` differs from the one in `self`
+    fn log_changes(&self, next: &Files, level: log::Level) {
+        for (path, contents) in &next.0 {
+            let old = self
+                .0
+                .iter()
+                .find(|(old, _)| old == path)
+                .map_or("", |(_, contents)| &contents[..]);
+            if old == contents {
+                continue;
+            }
+
+            let lines = diff(old, contents);
+            log::log!(level, "[reload] {path} changed:");
+            for line in lines.iter().take(MAX_DIFF_LINES) {
+                log::log!(level, "[reload]   {line}");
+            }
+            if lines.len() > MAX_DIFF_LINES {
+                log::log!(
+                    level,
+                    "[reload]   ... {} more line(s)",
+                    lines.len() - MAX_DIFF_LINES
+                );
+            }
+        }
+    }
+}
+
+// the lines removed from `old` and added in `new`, as "-line" and "+line",
+// around the longest common subsequence of both
+fn diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // the lines both start and end with are left out
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+
+    let removed = |line: &&str| format!("-{line}");
+    let added = |line: &&str| format!("+{line}");
+    if old.len() * new.len() > MAX_DIFF_CELLS {
+        return old
+            .iter()
+            .map(removed)
+            .chain(new.iter().map(added))
+            .collect();
+    }
+
+    // lcs[i][j] is the length of the longest common subsequence of old[i..]
+    // and new[j..]
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match old[i] == new[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(removed(&old[i]));
+            i += 1;
+        } else {
+            lines.push(added(&new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::parse(flags.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn restart_only_flags() {
+        let running = args(&["--resolve-ttl", "10", "--health-interval", "5"]);
+        assert!(restart_only_changes(&running, &running.clone()).is_empty());
+
+        let mut changed = running.clone();
+        changed.resolve_ttl = Duration::from_secs(20);
+        changed.drain_timeout = Duration::from_secs(1);
+        changed.health_interval = None;
+        assert_eq!(
+            restart_only_changes(&running, &changed),
+            ["--health-interval", "--resolve-ttl", "--drain-timeout"]
+        );
+
+        // the settings of new connections can change
+        let mut changed = running.clone();
+        changed.mark = 7;
+        changed.close_after = Duration::from_secs(1);
+        assert!(restart_only_changes(&running, &changed).is_empty());
+    }
+}
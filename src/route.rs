@@ -0,0 +1,499 @@
+use crate::{
+    header::tlv,
+    pool::{Entry, Lease, Pool},
+    tls::ClientHello,
+    util::Upstream,
+};
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    net::SocketAddr,
+    ops::RangeInclusive,
+    str::FromStr,
+};
+
+// what to do with transparent connections whose source family has no upstream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CrossFamily {
+    #[default]
+    Reject,
+    // use an upstream of the other family and re-emit the header to it
+    Reemit,
+}
+
+// what to do with connections that don't match any route
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Unmatched {
+    // forward them to the --ipv4 / --ipv6 upstreams
+    #[default]
+    Forward,
+    Reject,
+}
+
+impl FromStr for Unmatched {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "forward" => Ok(Self::Forward),
+            "reject" => Ok(Self::Reject),
+            _ => Err(format!("invalid unmatched value: {s}")),
+        }
+    }
+}
+
+// which of the client's v2 TLVs are passed on in a re-emitted header
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum TlvFilter {
+    #[default]
+    None,
+    All,
+    Only(Vec<u8>),
+}
+
+impl TlvFilter {
+    pub fn passes(&self, kind: u8) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Only(kinds) => kinds.contains(&kind),
+        }
+    }
+}
+
+// "none", "all" or a comma separated list of TLV names and numbers,
+// e.g. "authority,alpn,0xE0"
+impl FromStr for TlvFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => return Ok(Self::None),
+            "all" => return Ok(Self::All),
+            _ => {}
+        }
+
+        let kinds = s
+            .split(',')
+            .map(|name| match name {
+                "alpn" => Ok(tlv::PP2_TYPE_ALPN),
+                "authority" => Ok(tlv::PP2_TYPE_AUTHORITY),
+                "crc32c" => Ok(tlv::PP2_TYPE_CRC32C),
+                "unique-id" => Ok(tlv::PP2_TYPE_UNIQUE_ID),
+                "ssl" => Ok(tlv::PP2_TYPE_SSL),
+                "netns" => Ok(tlv::PP2_TYPE_NETNS),
+                "gcp" => Ok(tlv::PP2_TYPE_GCP),
+                "aws" => Ok(tlv::PP2_TYPE_AWS),
+                "azure" => Ok(tlv::PP2_TYPE_AZURE),
+                _ => match name.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16),
+                    None => name.parse(),
+                }
+                .map_err(|_| format!("invalid TLV: {name}")),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self::Only(kinds))
+    }
+}
+
+// dial the upstream from our own address and pass the client address on in
+// a fresh PROXY header, instead of binding to it with IP_TRANSPARENT
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendProxy {
+    pub version: u8,
+    // always empty for v1; a passed CRC32C is recomputed for the new header
+    pub tlvs: TlvFilter,
+}
+
+impl SendProxy {
+    // sent when nothing else is configured: to unix upstreams, which can't be
+    // reached transparently, and to upstreams of the other address family
+    pub const DEFAULT: Self = Self {
+        version: 2,
+        tlvs: TlvFilter::None,
+    };
+
+    pub fn new(version: &str, tlvs: TlvFilter) -> Result<Self, String> {
+        let version = match version {
+            "v1" | "1" => 1,
+            "v2" | "2" => 2,
+            _ => return Err(format!("invalid send-proxy version: {version}")),
+        };
+        if version == 1 && tlvs != TlvFilter::None {
+            return Err("v1 headers can't carry TLVs".into());
+        }
+
+        Ok(Self { version, tlvs })
+    }
+}
+
+// the backend a connection goes to, and how it gets there
+#[derive(Debug)]
+pub struct Target<'a> {
+    pub lease: Lease,
+    // `None` for transparent upstreams, except unix ones
+    pub send_proxy: Option<&'a SendProxy>,
+}
+
+impl Target<'_> {
+    // how the PROXY header is re-emitted, `None` when the upstream is reached
+    // transparently
+    pub fn reemit(&self) -> Option<&SendProxy> {
+        match (self.lease.upstream(), self.send_proxy) {
+            (Upstream::Inet(_), None) => None,
+            (_, send_proxy) => Some(send_proxy.unwrap_or(&SendProxy::DEFAULT)),
+        }
+    }
+}
+
+// a TLS server name, exact or with a leading "*." that matches any subdomain;
+// "*" alone matches every name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostPattern(String);
+
+impl HostPattern {
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        match self.0.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) => name.len() > suffix.len() && name.ends_with(suffix),
+            None => name == self.0,
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim_end_matches('.').to_lowercase();
+        let name = match pattern.strip_prefix('*') {
+            Some("") => return Ok(Self(pattern)),
+            Some(suffix) => suffix.strip_prefix('.'),
+            None => Some(&pattern[..]),
+        };
+
+        match name {
+            Some(name) if !name.is_empty() && !name.contains('*') => Ok(Self(pattern)),
+            _ => Err(format!("invalid host pattern: {s}")),
+        }
+    }
+}
+
+// matches the destination of the PROXY header and, for TLS, the ClientHello
+// that follows it, e.g.
+//
+//   # destination    ports       upstreams                          options
+//   10.0.0.0/8       443         127.0.0.1:8443 [::1]:8443          sni=*.example.com,example.com
+//   10.0.0.0/8       443         127.0.0.1:9443                     alpn=h2
+//   10.0.0.0/8       80          127.0.0.1:8000*3 127.0.0.1:8001     balance=least-conn
+//   192.0.2.10       8000-8100   unix:/run/app.sock
+//   *                *           10.1.0.1:9000                      send-proxy=v2 tlvs=authority
+#[derive(Debug, Clone)]
+pub struct Route {
+    // `None` matches every address
+    pub destination: Option<cidr::IpCidr>,
+    pub ports: RangeInclusive<u16>,
+    // any of these server names, when not empty
+    pub sni: Vec<HostPattern>,
+    // any of these protocols offered by the client, when not empty
+    pub alpn: Vec<String>,
+    pub upstreams: Pool,
+    pub send_proxy: Option<SendProxy>,
+}
+
+impl Route {
+    pub fn matches(&self, dst: &SocketAddr, hello: Option<&ClientHello>) -> bool {
+        self.matches_addr(dst) && self.matches_hello(hello)
+    }
+
+    pub fn matches_addr(&self, dst: &SocketAddr) -> bool {
+        let ip_matches = match self.destination {
+            Some(ref net) => net.contains(&dst.ip()),
+            None => true,
+        };
+
+        ip_matches && self.ports.contains(&dst.port())
+    }
+
+    // whether the ClientHello has to be peeked at to match this route
+    pub fn needs_hello(&self) -> bool {
+        !self.sni.is_empty() || !self.alpn.is_empty()
+    }
+
+    fn matches_hello(&self, hello: Option<&ClientHello>) -> bool {
+        if !self.needs_hello() {
+            return true;
+        }
+        let Some(hello) = hello else {
+            return false;
+        };
+
+        let sni_matches = self.sni.is_empty()
+            || hello
+                .sni
+                .as_deref()
+                .is_some_and(|name| self.sni.iter().any(|pattern| pattern.matches(name)));
+        let alpn_matches = self.alpn.is_empty()
+            || hello
+                .alpn
+                .iter()
+                .any(|protocol| self.alpn.contains(protocol));
+
+        sni_matches && alpn_matches
+    }
+
+    pub fn upstream_for(
+        &self,
+        src: &SocketAddr,
+        tried: &[Upstream],
+        cross_family: CrossFamily,
+    ) -> Option<Target<'_>> {
+        pick(
+            &self.upstreams,
+            src,
+            tried,
+            self.send_proxy.as_ref(),
+            cross_family,
+        )
+    }
+}
+
+// picks among the upstreams of `pool` that the source can reach, falling back
+// to the other family as the cross-family policy says
+pub fn pick<'a>(
+    pool: &Pool,
+    src: &SocketAddr,
+    tried: &[Upstream],
+    send_proxy: Option<&'a SendProxy>,
+    cross_family: CrossFamily,
+) -> Option<Target<'a>> {
+    let reemit = send_proxy.is_some();
+    let eligible = |upstream: &Upstream| reachable(upstream, src, reemit);
+    if let Some(lease) = pool.pick_untried(src.ip(), eligible, tried) {
+        return Some(Target { lease, send_proxy });
+    }
+    if reemit || cross_family == CrossFamily::Reject {
+        return None;
+    }
+
+    let lease = pool.pick_untried(src.ip(), |_| true, tried)?;
+    log::debug!(
+        "no upstream in the family of {src}, re-emitting the header to {}",
+        lease.upstream()
+    );
+    Some(Target {
+        lease,
+        send_proxy: Some(&SendProxy::DEFAULT),
+    })
+}
+
+// a transparent connection can only go to an upstream of the source's address
+// family, the others take either family
+pub fn reachable(upstream: &Upstream, src: &SocketAddr, reemit: bool) -> bool {
+    match upstream {
+        Upstream::Inet(addr) => reemit || addr.is_ipv4() == src.is_ipv4(),
+        Upstream::Unix(_) => true,
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut fields = line.split_whitespace();
+
+        let destination = match fields.next() {
+            Some("*") => None,
+            Some(net) => Some(cidr::IpCidr::from_str(net).map_err(|why| why.to_string())?),
+            None => return Err("missing destination".into()),
+        };
+        let ports = match fields.next() {
+            Some("*") => 0..=u16::MAX,
+            Some(ports) => parse_ports(ports).ok_or_else(|| format!("invalid ports: {ports}"))?,
+            None => return Err("missing ports".into()),
+        };
+
+        let mut backends = Vec::new();
+        let mut policy = None;
+        let mut version = None;
+        let mut tlvs = None;
+        let mut sni = Vec::new();
+        let mut alpn = Vec::new();
+        for field in fields {
+            match field.split_once('=') {
+                Some(("sni", value)) => {
+                    sni = value
+                        .split(',')
+                        .map(HostPattern::from_str)
+                        .collect::<Result<_, _>>()?
+                }
+                Some(("alpn", value)) => alpn = value.split(',').map(str::to_owned).collect(),
+                Some(("balance", value)) => policy = Some(value.parse()?),
+                Some(("send-proxy", value)) => version = Some(value),
+                Some(("tlvs", value)) => tlvs = Some(value.parse()?),
+                Some((option, _)) => return Err(format!("unknown option: {option}")),
+                None => backends.push(Entry::from_str(field)?),
+            }
+        }
+
+        if backends.is_empty() {
+            return Err("missing upstreams".into());
+        }
+        let mut upstreams = Pool::new(backends);
+        upstreams.policy = policy.unwrap_or_default();
+        let send_proxy = match (version, tlvs) {
+            (Some(version), tlvs) => Some(SendProxy::new(version, tlvs.unwrap_or_default())?),
+            (None, Some(_)) => return Err("tlvs requires send-proxy".into()),
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            destination,
+            ports,
+            sni,
+            alpn,
+            upstreams,
+            send_proxy,
+        })
+    }
+}
+
+// "443" or "8000-8100"
+fn parse_ports(ports: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+
+    (start <= end).then_some(start..=end)
+}
+
+// one route per line, blank lines and lines starting with '#' are skipped
+pub fn parse_routes(path: &str) -> io::Result<Vec<Route>> {
+    let mut data = Vec::new();
+    let mut file = File::open(path)?;
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match Route::from_str(line) {
+            Ok(route) => data.push(route),
+            Err(why) => {
+                return Err(io::Error::other(format!("{path}:{}: {why}", n + 1)));
+            }
+        }
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> HostPattern {
+        s.parse().unwrap()
+    }
+
+    fn hello(sni: Option<&str>, alpn: &[&str]) -> ClientHello {
+        ClientHello {
+            sni: sni.map(str::to_owned),
+            alpn: alpn.iter().map(|&protocol| protocol.to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(pattern("*").matches("example.com"));
+        assert!(pattern("*").matches("localhost"));
+
+        let wildcard = pattern("*.example.com");
+        assert!(wildcard.matches("www.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("wwwexample.com"));
+        assert!(!wildcard.matches("example.org"));
+
+        let exact = pattern("example.com");
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+    }
+
+    #[test]
+    fn host_patterns_ignore_case_and_trailing_dots() {
+        assert_eq!(pattern("Example.COM."), pattern("example.com"));
+        assert!(pattern("example.com").matches("EXAMPLE.com."));
+        assert!(pattern("*.example.com.").matches("www.Example.com."));
+        assert_eq!(pattern("*."), pattern("*"));
+    }
+
+    #[test]
+    fn invalid_host_patterns() {
+        for s in ["", ".", "*foo.com", "foo.*.com", "*.*.com", "www.*"] {
+            assert!(s.parse::<HostPattern>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn sni_and_alpn_options() {
+        let route: Route = "* 443 127.0.0.1:8443 sni=*.example.com,example.org alpn=h2,http/1.1"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            route.sni,
+            [pattern("*.example.com"), pattern("example.org")]
+        );
+        assert_eq!(route.alpn, ["h2", "http/1.1"]);
+        assert!(route.needs_hello());
+
+        let dst = "192.0.2.1:443".parse().unwrap();
+        let matches = |hello: &ClientHello| route.matches(&dst, Some(hello));
+        assert!(matches(&hello(Some("www.example.com"), &["h2"])));
+        assert!(matches(&hello(
+            Some("example.org"),
+            &["acme-tls/1", "http/1.1"]
+        )));
+        assert!(!matches(&hello(Some("example.com"), &["h2"])));
+        assert!(!matches(&hello(Some("www.example.com"), &["h3"])));
+        assert!(!matches(&hello(None, &["h2"])));
+        assert!(!route.matches(&dst, None));
+        assert!(!route.matches(
+            &"192.0.2.1:80".parse().unwrap(),
+            Some(&hello(Some("example.org"), &["h2"]))
+        ));
+    }
+
+    #[test]
+    fn sni_or_alpn_alone() {
+        let dst = "192.0.2.1:443".parse().unwrap();
+
+        let route: Route = "* * 127.0.0.1:8443 sni=example.com".parse().unwrap();
+        assert!(route.matches(&dst, Some(&hello(Some("example.com"), &[]))));
+        assert!(!route.matches(&dst, Some(&hello(Some("example.org"), &["h2"]))));
+
+        let route: Route = "* * 127.0.0.1:8443 alpn=h2".parse().unwrap();
+        assert!(route.matches(&dst, Some(&hello(None, &["http/1.1", "h2"]))));
+        assert!(!route.matches(&dst, Some(&hello(Some("example.com"), &[]))));
+
+        // a route without either matches with or without a ClientHello
+        let route: Route = "* * 127.0.0.1:8443".parse().unwrap();
+        assert!(!route.needs_hello());
+        assert!(route.matches(&dst, None));
+        assert!(route.matches(&dst, Some(&hello(Some("example.com"), &["h2"]))));
+    }
+
+    #[test]
+    fn invalid_sni_option() {
+        assert!("* * 127.0.0.1:8443 sni=*foo.com".parse::<Route>().is_err());
+        assert!("* * 127.0.0.1:8443 sni=example.com,"
+            .parse::<Route>()
+            .is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use simple_eyre::eyre::{Result, WrapErr};
+
+use std::{
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+// only ever moves forward
+static STATE: OnceLock<watch::Sender<State>> = OnceLock::new();
+// TCP connections that are still being proxied
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+// UDP sessions that are still open
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+// UDP sessions that were cut short by `terminate`
+static CLOSED_SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum State {
+    Running,
+    // the listeners stop taking new connections, the ones they already have
+    // are drained, UDP sessions included
+    Stopped,
+    // like `Stopped`, but the UDP sessions are closed right away
+    Terminated,
+}
+
+fn sender() -> &'static watch::Sender<State> {
+    STATE.get_or_init(|| watch::channel(State::Running).0)
+}
+
+fn advance(state: State) {
+    sender().send_if_modified(|current| {
+        let advanced = *current < state;
+        if advanced {
+            *current = state;
+        }
+        advanced
+    });
+}
+
+// once another process has taken the listeners over
+pub fn stop() {
+    advance(State::Stopped);
+}
+
+pub fn terminate() {
+    advance(State::Terminated);
+}
+
+pub fn is_stopping() -> bool {
+    *sender().borrow() >= State::Stopped
+}
+
+// on SIGTERM or SIGINT, terminates so that main can drain the connections,
+// also after a hot upgrade; a second signal exits right away
+pub fn watch() -> Result<()> {
+    let mut term = signal(SignalKind::terminate()).wrap_err("failed to listen for SIGTERM")?;
+    let mut int = signal(SignalKind::interrupt()).wrap_err("failed to listen for SIGINT")?;
+
+    tokio::spawn(async move {
+        let mut received = 0;
+        loop {
+            let name = tokio::select! {
+                _ = term.recv() => "SIGTERM",
+                _ = int.recv() => "SIGINT",
+            };
+            received += 1;
+
+            if received > 1 {
+                log::warn!(
+                    "{name} received again, exiting with {} TCP connection(s) open",
+                    connections()
+                );
+                process::exit(1);
+            }
+            log::info!("{name} received, shutting down");
+            terminate();
+        }
+    });
+    Ok(())
+}
+
+// resolves once `stop` or `terminate` has been called
+pub async fn stopped() {
+    reached(State::Stopped).await
+}
+
+// resolves once `terminate` has been called
+pub async fn terminated() {
+    reached(State::Terminated).await
+}
+
+async fn reached(state: State) {
+    let mut rx = sender().subscribe();
+    while *rx.borrow_and_update() < state {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+// counts a TCP connection for as long as it's alive
+pub struct Connection(());
+
+impl Connection {
+    pub fn new() -> Self {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn connections() -> usize {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+// counts a UDP session for as long as it's alive
+pub struct Session(());
+
+impl Session {
+    pub fn new() -> Self {
+        SESSIONS.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        SESSIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn sessions() -> usize {
+    SESSIONS.load(Ordering::Relaxed)
+}
+
+pub fn closed_sessions(n: usize) {
+    CLOSED_SESSIONS.fetch_add(n, Ordering::Relaxed);
+}
+
+// resolves once every TCP connection and UDP session is closed
+pub async fn drained() {
+    while connections() > 0 || sessions() > 0 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// waits up to `timeout` for the TCP connections to finish and the UDP sessions
+// to go idle, and logs what had to be closed; the ones still open are closed
+// when the process exits
+pub async fn drain(timeout: Duration) {
+    if connections() > 0 || sessions() > 0 {
+        log::info!(
+            "draining {} TCP connection(s) and {} UDP session(s) for up to {}s",
+            connections(),
+            sessions(),
+            timeout.as_secs()
+        );
+    }
+
+    let (forced, open) = match tokio::time::timeout(timeout, drained()).await {
+        Ok(()) => (0, 0),
+        Err(_) => (connections(), sessions()),
+    };
+    let sessions = CLOSED_SESSIONS.load(Ordering::Relaxed) + open;
+    if forced > 0 || sessions > 0 {
+        log::warn!("force-closed {forced} TCP connection(s) and {sessions} UDP session(s)");
+    } else {
+        log::info!("every connection finished");
+    }
+}
@@ -0,0 +1,301 @@
+// just enough of a TLS ClientHello parser to route connections by SNI and ALPN,
+// the handshake itself is left to the upstream
+
+const CONTENT_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_ALPN: u16 = 16;
+const SERVER_NAME_HOST: u8 = 0;
+
+// a plaintext record can't be longer than 2^14 bytes, some implementations
+// allow a little more
+const MAX_RECORD_LEN: usize = 16 * 1024 + 2048;
+// ClientHellos with post-quantum key shares and many extensions can span a few
+// records, anything bigger isn't worth waiting for
+pub const MAX_HELLO_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientHello {
+    pub sni: Option<String>,
+    // protocols the client offers, in its order of preference
+    pub alpn: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Peek {
+    Incomplete,
+    // not TLS, or a ClientHello that can't be parsed
+    NotTls,
+    Hello(ClientHello),
+}
+
+// looks for a complete ClientHello at the start of `data`, which may be split
+// across several handshake records
+pub fn peek(data: &[u8]) -> Peek {
+    let mut message = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let record = &data[pos..];
+        match record {
+            [] => return Peek::Incomplete,
+            [kind, ..] if *kind != CONTENT_HANDSHAKE => return Peek::NotTls,
+            [_, major, ..] if *major != 3 => return Peek::NotTls,
+            [_, _, _, hi, lo, ..] => {
+                let len = u16::from_be_bytes([*hi, *lo]) as usize;
+                if len == 0 || len > MAX_RECORD_LEN {
+                    return Peek::NotTls;
+                }
+                match record.get(5..5 + len) {
+                    Some(fragment) => message.extend_from_slice(fragment),
+                    None => return Peek::Incomplete,
+                }
+                pos += 5 + len;
+            }
+            _ => return Peek::Incomplete,
+        }
+
+        if let [kind, a, b, c, ..] = message[..] {
+            if kind != HANDSHAKE_CLIENT_HELLO {
+                return Peek::NotTls;
+            }
+            let len = u32::from_be_bytes([0, a, b, c]) as usize;
+            if len + 4 > MAX_HELLO_LEN {
+                return Peek::NotTls;
+            }
+            if let Some(body) = message.get(4..4 + len) {
+                return match parse_client_hello(body) {
+                    Some(hello) => Peek::Hello(hello),
+                    None => Peek::NotTls,
+                };
+            }
+        }
+    }
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader(body);
+    // legacy version and random
+    reader.take(2 + 32)?;
+    let session_id_len = reader.u8()? as usize;
+    reader.take(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.take(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.take(compression_len)?;
+
+    let mut hello = ClientHello::default();
+    // extensions are optional before TLS 1.2
+    if reader.0.is_empty() {
+        return Some(hello);
+    }
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = Reader(extensions.take(len)?);
+
+        match kind {
+            EXT_SERVER_NAME => {
+                let list_len = data.u16()? as usize;
+                let mut names = Reader(data.take(list_len)?);
+                while !names.0.is_empty() {
+                    let name_type = names.u8()?;
+                    let name_len = names.u16()? as usize;
+                    let name = names.take(name_len)?;
+                    if name_type == SERVER_NAME_HOST {
+                        hello.sni = Some(String::from_utf8(name.to_vec()).ok()?);
+                    }
+                }
+            }
+            EXT_ALPN => {
+                let list_len = data.u16()? as usize;
+                let mut protocols = Reader(data.take(list_len)?);
+                while !protocols.0.is_empty() {
+                    let len = protocols.u8()? as usize;
+                    let protocol = protocols.take(len)?;
+                    hello
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(hello)
+}
+
+// reads big endian fields off the front of a slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|data| data[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|data| u16::from_be_bytes([data[0], data[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_be_bytes().to_vec();
+        out.extend((data.len() as u16).to_be_bytes());
+        out.extend(data);
+        out
+    }
+
+    fn server_name(name: &str) -> Vec<u8> {
+        let mut entry = vec![SERVER_NAME_HOST];
+        entry.extend((name.len() as u16).to_be_bytes());
+        entry.extend(name.as_bytes());
+
+        let mut list = (entry.len() as u16).to_be_bytes().to_vec();
+        list.extend(entry);
+        extension(EXT_SERVER_NAME, &list)
+    }
+
+    fn alpn(protocols: &[&str]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for protocol in protocols {
+            entries.push(protocol.len() as u8);
+            entries.extend(protocol.as_bytes());
+        }
+
+        let mut list = (entries.len() as u16).to_be_bytes().to_vec();
+        list.extend(entries);
+        extension(EXT_ALPN, &list)
+    }
+
+    // a ClientHello handshake message with these extensions, or none at all
+    fn client_hello(extensions: Option<&[u8]>) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend([0; 32]);
+        // no session id, one cipher suite, no compression
+        body.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        if let Some(extensions) = extensions {
+            body.extend((extensions.len() as u16).to_be_bytes());
+            body.extend(extensions);
+        }
+
+        let mut out = vec![HANDSHAKE_CLIENT_HELLO];
+        out.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        out.extend(body);
+        out
+    }
+
+    // wraps a handshake message in records of at most `len` bytes each
+    fn records(message: &[u8], len: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for fragment in message.chunks(len) {
+            out.extend([CONTENT_HANDSHAKE, 3, 1]);
+            out.extend((fragment.len() as u16).to_be_bytes());
+            out.extend(fragment);
+        }
+        out
+    }
+
+    fn hello(sni: Option<&str>, alpn: &[&str]) -> Peek {
+        Peek::Hello(ClientHello {
+            sni: sni.map(str::to_owned),
+            alpn: alpn.iter().map(|&protocol| protocol.to_owned()).collect(),
+        })
+    }
+
+    #[test]
+    fn sni_and_alpn() {
+        let extensions = [server_name("example.com"), alpn(&["h2", "http/1.1"])].concat();
+        let data = records(&client_hello(Some(&extensions)), MAX_RECORD_LEN);
+        assert_eq!(peek(&data), hello(Some("example.com"), &["h2", "http/1.1"]));
+
+        let extensions = [extension(0xff01, &[0]), alpn(&["acme-tls/1"])].concat();
+        let data = records(&client_hello(Some(&extensions)), MAX_RECORD_LEN);
+        assert_eq!(peek(&data), hello(None, &["acme-tls/1"]));
+        let data = records(&client_hello(None), MAX_RECORD_LEN);
+        assert_eq!(peek(&data), hello(None, &[]));
+    }
+
+    #[test]
+    fn split_across_records() {
+        let extensions = [server_name("example.com"), alpn(&["h2"])].concat();
+        let data = records(&client_hello(Some(&extensions)), 16);
+        assert_eq!(peek(&data), hello(Some("example.com"), &["h2"]));
+
+        // and whatever the client sends after it is left alone
+        let data = [&data[..], b"\x14\x03\x03\x00\x01\x01"].concat();
+        assert_eq!(peek(&data), hello(Some("example.com"), &["h2"]));
+    }
+
+    #[test]
+    fn incomplete() {
+        let extensions = [server_name("example.com"), alpn(&["h2"])].concat();
+        for len in [16, MAX_RECORD_LEN] {
+            let data = records(&client_hello(Some(&extensions)), len);
+            for end in 0..data.len() {
+                assert_eq!(peek(&data[..end]), Peek::Incomplete, "{len} {end}");
+            }
+        }
+    }
+
+    #[test]
+    fn not_tls() {
+        let data = records(&client_hello(None), MAX_RECORD_LEN);
+        assert_eq!(peek(b"GET / HTTP/1.1\r\n"), Peek::NotTls);
+        assert_eq!(peek(b"SSH-2.0-OpenSSH_9.6\r\n"), Peek::NotTls);
+
+        // application data, SSLv2 and an empty record
+        assert_eq!(peek(&[&[23][..], &data[1..]].concat()), Peek::NotTls);
+        assert_eq!(
+            peek(&[&[CONTENT_HANDSHAKE, 2], &data[2..]].concat()),
+            Peek::NotTls
+        );
+        assert_eq!(peek(&[CONTENT_HANDSHAKE, 3, 1, 0, 0]), Peek::NotTls);
+
+        // a ServerHello, and a handshake message too big to wait for
+        let mut server_hello = client_hello(None);
+        server_hello[0] = 2;
+        assert_eq!(peek(&records(&server_hello, MAX_RECORD_LEN)), Peek::NotTls);
+        let huge = [HANDSHAKE_CLIENT_HELLO, 0x01, 0x00, 0x00];
+        assert_eq!(peek(&records(&huge, MAX_RECORD_LEN)), Peek::NotTls);
+    }
+
+    #[test]
+    fn too_short_for_its_extensions() {
+        let extensions = server_name("example.com");
+        let mut message = client_hello(Some(&extensions));
+
+        // the extensions claim one byte more than the body has left
+        let at = message.len() - extensions.len() - 2;
+        message[at + 1] += 1;
+        assert_eq!(peek(&records(&message, MAX_RECORD_LEN)), Peek::NotTls);
+
+        // and so does the server name extension
+        let mut message = client_hello(Some(&extensions));
+        let at = message.len() - extensions.len() + 2;
+        message[at + 1] += 1;
+        assert_eq!(peek(&records(&message, MAX_RECORD_LEN)), Peek::NotTls);
+
+        // a server name that isn't UTF-8
+        let mut extensions = server_name("example.com");
+        *extensions.last_mut().unwrap() = 0xff;
+        let data = records(&client_hello(Some(&extensions)), MAX_RECORD_LEN);
+        assert_eq!(peek(&data), Peek::NotTls);
+    }
+}
@@ -0,0 +1,223 @@
+use simple_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::{activation::Sockets, shutdown};
+use std::{
+    env,
+    io::{self, Read, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    process::Command,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+// the new process finds its end of the socket to the old one in this variable
+const UPGRADE_FD: &str = "MMPROXY_UPGRADE_FD";
+// as many fds as a single SCM_RIGHTS message can carry
+const MAX_FDS: usize = 253;
+// how long the new process has to take the listeners over
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// duplicates of the listening sockets, with the --fd-name of their listener,
+// which can be handed over while the listeners keep using theirs
+static LISTENING: Mutex<Vec<(String, OwnedFd)>> = Mutex::new(Vec::new());
+
+pub fn register(name: Option<&str>, fd: BorrowedFd) -> io::Result<()> {
+    let fd = fd.try_clone_to_owned()?;
+    LISTENING
+        .lock()
+        .unwrap()
+        .push((name.unwrap_or_default().to_owned(), fd));
+    Ok(())
+}
+
+// once the listeners have stopped, so that their sockets are closed and new
+// clients are refused instead of waiting in the backlog
+pub fn release() {
+    LISTENING.lock().unwrap().clear();
+}
+
+// on SIGUSR2, starts the binary again with the same arguments and hands the
+// listening sockets over to it; once the new process has taken them over, this
+// one stops accepting and drains its connections
+pub fn watch() -> Result<()> {
+    let mut usr2 = signal(SignalKind::user_defined2()).wrap_err("failed to listen for SIGUSR2")?;
+
+    tokio::spawn(async move {
+        while usr2.recv().await.is_some() {
+            log::info!("SIGUSR2 received, upgrading");
+            match tokio::task::spawn_blocking(hand_over).await {
+                Ok(Ok(pid)) => {
+                    log::info!("process {pid} took over the listeners, draining");
+                    shutdown::stop();
+                    return;
+                }
+                Ok(Err(why)) => log::error!("hot upgrade failed: {why:#}"),
+                Err(why) => log::error!("hot upgrade failed: {why}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+// returns the pid of the new process once it's listening
+fn hand_over() -> Result<u32> {
+    let (mut ours, theirs) = UnixStream::pair().wrap_err("failed to create the upgrade socket")?;
+    let mut argv = env::args_os();
+    let program = argv
+        .next()
+        .ok_or_else(|| eyre!("the program path is unknown"))?;
+
+    // their end of the socket is the only fd that the new process inherits
+    let fd = theirs.as_raw_fd();
+    let mut command = Command::new(program);
+    command.args(argv).env(UPGRADE_FD, fd.to_string());
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+            ret if ret < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let mut child = command
+        .spawn()
+        .wrap_err("failed to start the new process")?;
+    drop(theirs);
+    let pid = child.id();
+
+    let ret = (|| {
+        let listening = LISTENING.lock().unwrap();
+        // never empty, so the new process can tell it from a closed socket
+        let names: Vec<_> = listening.iter().map(|(name, _)| &name[..]).collect();
+        let names = format!("{}\n", names.join(":"));
+        let fds: Vec<_> = listening.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+        send_fds(&ours, names.as_bytes(), &fds).wrap_err("failed to send the sockets")?;
+        drop(listening);
+
+        ours.set_read_timeout(Some(TAKEOVER_TIMEOUT))?;
+        let mut ack = [0u8; 1];
+        match ours.read(&mut ack) {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(eyre!("it exited before taking over")),
+            Err(why) => Err(why).wrap_err("it didn't take over in time"),
+        }
+    })();
+
+    if let Err(why) = ret {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(why).wrap_err_with(|| format!("process {pid}"));
+    }
+    Ok(pid)
+}
+
+// in a process started by a hot upgrade, the listening sockets of the old
+// process, and the socket to tell it that they've been taken over
+pub fn inherited() -> Result<Option<(UnixStream, Sockets)>> {
+    let Ok(fd) = env::var(UPGRADE_FD) else {
+        return Ok(None);
+    };
+    env::remove_var(UPGRADE_FD);
+
+    let fd: RawFd = fd
+        .parse()
+        .map_err(|_| eyre!("invalid {UPGRADE_FD}: {fd}"))?;
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error()).wrap_err("invalid upgrade socket");
+    }
+    // the old process made this fd for us
+    let conn = unsafe { UnixStream::from_raw_fd(fd) };
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    let (read, fds) = recv_fds(&conn, &mut buffer).wrap_err("failed to receive the sockets")?;
+    let names = String::from_utf8_lossy(&buffer[..read]);
+    let Some(names) = names.strip_suffix('\n') else {
+        return Err(eyre!("the old process closed the upgrade socket"));
+    };
+
+    log::info!("took over {} listening socket(s)", fds.len());
+    Ok(Some((conn, Sockets::from_fds(&fds, names)?)))
+}
+
+// tells the old process that it can stop accepting
+pub fn took_over(mut conn: UnixStream) {
+    if let Err(why) = conn.write_all(b"1") {
+        log::error!("failed to tell the old process to stop: {why}");
+    }
+}
+
+fn send_fds(sock: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() > MAX_FDS {
+        return Err(io::Error::other("too many listening sockets"));
+    }
+
+    let fds_len = mem::size_of_val(fds) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut _,
+        iov_len: data.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len() as _;
+        unsafe {
+            let header = libc::CMSG_FIRSTHDR(&msg);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(header).cast(), fds.len());
+        }
+    }
+
+    match unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) } {
+        sent if sent < 0 => Err(io::Error::last_os_error()),
+        sent if (sent as usize) < data.len() => Err(io::Error::other("short write")),
+        _ => Ok(()),
+    }
+}
+
+fn recv_fds(sock: &UnixStream, buffer: &mut [u8]) -> io::Result<(usize, Vec<RawFd>)> {
+    let fds_len = (MAX_FDS * mem::size_of::<RawFd>()) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+
+    let read = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&msg);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(data.add(i).read_unaligned());
+                }
+            }
+            header = libc::CMSG_NXTHDR(&msg, header);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::other("some of the sockets were cut off"));
+    }
+
+    Ok((read as usize, fds))
+}
@@ -1,28 +1,249 @@
-use simple_eyre::eyre::{Result, WrapErr};
+use simple_eyre::eyre::{Report, Result, WrapErr};
 
 use std::{
+    fmt,
     fs::File,
     io::{self, Read},
-    net::{IpAddr, SocketAddr},
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::{
+        fd::RawFd,
+        linux::net::SocketAddrExt,
+        unix::net::{self as std_unix, SocketAddr as UnixAddr},
+    },
+    path::{Path, PathBuf},
+    process,
     str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-use proxy_protocol::{version1 as v1, version2 as v2, ProxyHeader};
+use crate::{
+    header::{
+        self,
+        tlv::{self, Tlvs},
+        Addresses, Command, ParseResult, Transport,
+    },
+    metrics::{self, Counter},
+    route::SendProxy,
+};
 use socket2::{Domain, SockRef, Socket, Type};
-use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket, UnixDatagram};
 
 // this is returned from `util::parse_proxy_protocol_header` function
-pub type ProxyProtocolResult<'a> = io::Result<(Option<(SocketAddr, SocketAddr)>, &'a [u8], i32)>;
+pub type ProxyProtocolResult<'a> = io::Result<ParsedHeader<'a>>;
+
+#[derive(Debug)]
+pub struct ParsedHeader<'a> {
+    // `Unspec` if there was no header or it doesn't carry addresses
+    pub addresses: Addresses<'a>,
+    // the bytes that followed the header
+    pub rest: &'a [u8],
+    // 0 if the connection didn't carry a header
+    pub version: u8,
+    pub command: Command,
+    pub tlvs: Tlvs,
+    // the undecoded TLV block, for re-emitting the header
+    pub raw_tlvs: &'a [u8],
+}
+
+// lookup table for the reflected CRC-32C (Castagnoli) polynomial
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// what to do with a v2 header whose PP2_TYPE_CRC32C checksum doesn't match
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumMode {
+    #[default]
+    Reject,
+    Log,
+}
+
+// whether the connections of a listener must carry a proxy protocol header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderPolicy {
+    #[default]
+    Required,
+    // the header is parsed if present, otherwise the data is passed through
+    Optional,
+    // connections that carry a header are rejected
+    Forbidden,
+}
+
+// the proxy protocol versions a listener accepts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderVersions {
+    V1,
+    V2,
+    #[default]
+    Any,
+}
+
+impl HeaderVersions {
+    pub fn accepts(self, version: u8) -> bool {
+        matches!(
+            (self, version),
+            (Self::Any, _) | (Self::V1, 1) | (Self::V2, 2)
+        )
+    }
+}
+
+// what to do with connections whose v2 header carries the LOCAL command,
+// which load balancers use for their health checks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LocalAction {
+    // proxy them like any other connection, from the load balancer's address
+    #[default]
+    Proxy,
+    Close,
+    // reply with a fixed banner, then close
+    Banner,
+    // forward them to the health upstream without IP_TRANSPARENT
+    Forward,
+}
+
+// where the traffic of an address family is forwarded to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Upstream {
+    Inet(SocketAddr),
+    // IP_TRANSPARENT doesn't apply to unix sockets, so the client address is
+    // passed on in a fresh PROXY header instead
+    Unix(PathBuf),
+}
+
+impl FromStr for Upstream {
+    type Err = AddrParseError;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => s.parse().map(Self::Inet),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inet(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// why connecting to an upstream failed, so that operators can tell a dead
+// backend from a broken setup
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialError {
+    Refused,
+    Timeout,
+    Unreachable,
+    // the client's address and port are already connected to the upstream,
+    // which happens with transparent sockets when clients reuse their ports
+    AddrInUse,
+    // IP_TRANSPARENT needs CAP_NET_ADMIN
+    PermissionDenied,
+    Other,
+}
+
+impl DialError {
+    pub fn classify(why: &Report) -> Self {
+        let errno = why
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .and_then(|why| why.raw_os_error());
+
+        match errno {
+            Some(libc::ECONNREFUSED) => Self::Refused,
+            Some(libc::ETIMEDOUT) => Self::Timeout,
+            Some(libc::ENETUNREACH | libc::EHOSTUNREACH | libc::ENETDOWN | libc::EHOSTDOWN) => {
+                Self::Unreachable
+            }
+            Some(libc::EADDRINUSE | libc::EADDRNOTAVAIL) => Self::AddrInUse,
+            Some(libc::EPERM | libc::EACCES) => Self::PermissionDenied,
+            _ => Self::Other,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Refused => "the upstream refused the connection",
+            Self::Timeout => "the upstream didn't answer in time",
+            Self::Unreachable => "the upstream is unreachable",
+            Self::AddrInUse => "the client address is already in use towards the upstream",
+            Self::PermissionDenied => "permission denied, IP_TRANSPARENT needs CAP_NET_ADMIN",
+            Self::Other => "failed to connect to the upstream",
+        }
+    }
+
+    pub fn counter(self) -> &'static Counter {
+        match self {
+            Self::Refused => &metrics::DIAL_REFUSED,
+            Self::Timeout => &metrics::DIAL_TIMEOUT,
+            Self::Unreachable => &metrics::DIAL_UNREACHABLE,
+            Self::AddrInUse => &metrics::DIAL_ADDR_IN_USE,
+            Self::PermissionDenied => &metrics::DIAL_PERMISSION_DENIED,
+            Self::Other => &metrics::DIAL_OTHER,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub enum Protocol {
+    #[default]
     Tcp,
     Udp,
 }
 
-impl Default for Protocol {
-    fn default() -> Self {
-        Self::Tcp
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &s.to_lowercase()[..] {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(format!("invalid protocol value: {s}")),
+        }
+    }
+}
+
+// "tcp", "udp", "both" or a comma separated list like "tcp,udp"
+pub fn parse_protocols(s: &str) -> Result<Vec<Protocol>, String> {
+    if s.eq_ignore_ascii_case("both") {
+        return Ok(vec![Protocol::Tcp, Protocol::Udp]);
+    }
+
+    let mut protocols = Vec::new();
+    for protocol in s.split(',') {
+        let protocol = protocol.parse()?;
+        if !protocols.contains(&protocol) {
+            protocols.push(protocol);
+        }
+    }
+    Ok(protocols)
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp => write!(f, "tcp"),
+            Self::Udp => write!(f, "udp"),
+        }
     }
 }
 
@@ -47,7 +268,7 @@ pub fn parse_allowed_subnets(path: &str) -> io::Result<Vec<cidr::IpCidr>> {
         match cidr::IpCidr::from_str(line) {
             Ok(cidr) => data.push(cidr),
             Err(why) => {
-                return Err(io::Error::new(io::ErrorKind::Other, why));
+                return Err(io::Error::other(why));
             }
         }
     }
@@ -55,6 +276,94 @@ pub fn parse_allowed_subnets(path: &str) -> io::Result<Vec<cidr::IpCidr>> {
     Ok(data)
 }
 
+// IPv4-mapped (::ffff:a.b.c.d) and IPv4-compatible (::a.b.c.d) addresses
+// belong to IPv4 clients, e.g. behind a dual-stack socket; :: and ::1 keep
+// their IPv6 meaning
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) if !v6.ip().is_unspecified() && !v6.ip().is_loopback() => {
+            match v6.ip().to_ipv4() {
+                Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+                None => addr,
+            }
+        }
+        _ => addr,
+    }
+}
+
+// TCP_DEFER_ACCEPT isn't exposed by socket2
+pub fn set_defer_accept(fd: RawFd, secs: u32) -> io::Result<()> {
+    let secs =
+        libc::c_int::try_from(secs).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_DEFER_ACCEPT,
+            (&secs as *const libc::c_int).cast(),
+            std::mem::size_of_val(&secs) as libc::socklen_t,
+        )
+    };
+
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// pins the calling thread to the n-th of the CPUs that it may run on, wrapping
+// around; returns that CPU
+pub fn pin_to_cpu(n: usize) -> io::Result<usize> {
+    let size = std::mem::size_of::<libc::cpu_set_t>();
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sched_getaffinity(0, size, &mut set) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let allowed: Vec<usize> = (0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect();
+    let cpu = match allowed.len() {
+        0 => return Err(io::Error::other("no CPU to run on")),
+        len => allowed[n % len],
+    };
+
+    unsafe {
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+    }
+    if unsafe { libc::sched_setaffinity(0, size, &set) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(cpu)
+}
+
+// turns the \r, \n, \t and \\ escapes of a command-line argument
+// into the bytes they stand for
+pub fn unescape(arg: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(arg.len());
+    let mut bytes = arg.bytes();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            out.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'r') => out.push(b'\r'),
+            Some(b'n') => out.push(b'\n'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'\\') => out.push(b'\\'),
+            Some(other) => out.extend([b'\\', other]),
+            None => out.push(b'\\'),
+        }
+    }
+
+    out
+}
+
 fn setup_socket(socket_ref: &SockRef, src: SocketAddr, mark: u32) -> Result<()> {
     // needs CAP_NET_ADMIN
     socket_ref
@@ -99,6 +408,63 @@ pub async fn tcp_create_upstream_conn(
         .wrap_err("failed to connect to the upstream server")
 }
 
+// an upstream connection from our own address, for traffic that must not be
+// sent from the client's address
+pub async fn tcp_create_local_conn(target: SocketAddr) -> Result<TcpStream> {
+    let stream = TcpStream::connect(target)
+        .await
+        .wrap_err_with(|| format!("failed to connect to {target}"))?;
+    stream
+        .set_nodelay(true)
+        .wrap_err("failed to set nodelay on the upstream socket")?;
+
+    Ok(stream)
+}
+
+// an upstream socket bound to our own address, for traffic that must not be
+// sent from the client's address
+pub async fn udp_create_local_conn(target: SocketAddr) -> Result<UdpSocket> {
+    let bind_addr: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr)
+        .await
+        .wrap_err("failed to create upstream socket")?;
+
+    socket
+        .connect(target)
+        .await
+        .wrap_err("failed to connect to the upstream server")?;
+
+    Ok(socket)
+}
+
+// unix datagram sockets need an address of their own to receive replies, so
+// each one is bound to a unique abstract name
+pub fn udp_create_unix_conn(target: &Path) -> Result<UnixDatagram> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let name = format!("mmproxy-{}-{id}", process::id());
+    let addr = UnixAddr::from_abstract_name(name.as_bytes())
+        .wrap_err("failed to create the upstream socket address")?;
+
+    let socket =
+        std_unix::UnixDatagram::bind_addr(&addr).wrap_err("failed to create upstream socket")?;
+    socket
+        .set_nonblocking(true)
+        .wrap_err("failed to set nonblocking on the upstream socket")?;
+    let socket =
+        UnixDatagram::from_std(socket).wrap_err("failed to cast std socket to tokio socket")?;
+
+    socket
+        .connect(target)
+        .wrap_err_with(|| format!("failed to connect to unix:{}", target.display()))?;
+
+    Ok(socket)
+}
+
 pub async fn udp_create_upstream_conn(
     src: SocketAddr,
     target: SocketAddr,
@@ -122,54 +488,244 @@ pub async fn udp_create_upstream_conn(
     Ok(udp_socket)
 }
 
-// TODO: revise this
-pub fn parse_proxy_protocol_header(mut buffer: &[u8]) -> ProxyProtocolResult {
-    match proxy_protocol::parse(&mut buffer) {
-        Ok(result) => match result {
-            ProxyHeader::Version1 { addresses } => match addresses {
-                v1::ProxyAddresses::Unknown => Ok((None, buffer, 1)),
-                v1::ProxyAddresses::Ipv4 {
-                    source,
-                    destination,
-                } => Ok((
-                    Some((SocketAddr::V4(source), SocketAddr::V4(destination))),
-                    buffer,
-                    1,
-                )),
-                v1::ProxyAddresses::Ipv6 {
-                    source,
-                    destination,
-                } => Ok((
-                    Some((SocketAddr::V6(source), SocketAddr::V6(destination))),
-                    buffer,
-                    1,
-                )),
-            },
-            ProxyHeader::Version2 { addresses, .. } => match addresses {
-                v2::ProxyAddresses::Unspec => Ok((None, buffer, 2)),
-                v2::ProxyAddresses::Ipv4 {
-                    source,
-                    destination,
-                } => Ok((
-                    Some((SocketAddr::V4(source), SocketAddr::V4(destination))),
-                    buffer,
-                    2,
-                )),
-                v2::ProxyAddresses::Ipv6 {
-                    source,
-                    destination,
-                } => Ok((
-                    Some((SocketAddr::V6(source), SocketAddr::V6(destination))),
-                    buffer,
-                    2,
-                )),
-                v2::ProxyAddresses::Unix { .. } => Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "unix sockets are not supported",
-                )),
-            },
-            _ => unreachable!(),
-        },
-        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+// the PROXY header that is re-emitted to a non-transparent upstream; `tlvs` is
+// the raw TLV block of the client's header, filtered as the route says
+pub fn encode_proxy_header(
+    send_proxy: &SendProxy,
+    transport: Transport,
+    addresses: &Addresses<'_>,
+    tlvs: &[u8],
+) -> Vec<u8> {
+    if send_proxy.version == 1 {
+        return header::encode_v1(addresses);
+    }
+
+    let mut passed = Vec::new();
+    let mut checksum = false;
+    for tlv in tlv::iter(tlvs).flatten() {
+        if !send_proxy.tlvs.passes(tlv.kind) {
+            continue;
+        }
+        // the old checksum doesn't cover the new header, so a fresh one is
+        // computed over it with the value zeroed out, and patched in at the end
+        if tlv.kind == tlv::PP2_TYPE_CRC32C {
+            checksum = true;
+            continue;
+        }
+        passed.push(tlv.kind);
+        passed.extend_from_slice(&(tlv.value.len() as u16).to_be_bytes());
+        passed.extend_from_slice(tlv.value);
+    }
+
+    let max = header::max_v2_tlvs_len(addresses) - if checksum { 7 } else { 0 };
+    // e.g. IPv4 clients with a full header, passed on to a unix socket
+    if passed.len() > max {
+        log::warn!(
+            "dropping {} bytes of TLVs that don't fit in the new header",
+            passed.len()
+        );
+        passed.clear();
+    }
+    if checksum {
+        passed.extend_from_slice(&[tlv::PP2_TYPE_CRC32C, 0, 4, 0, 0, 0, 0]);
+    }
+
+    let mut out = header::encode_v2(Command::Proxy, transport, addresses, &passed);
+    if checksum {
+        let crc = crc32c(&[&out]);
+        let len = out.len();
+        out[len - 4..].copy_from_slice(&crc.to_be_bytes());
+    }
+
+    out
+}
+
+// applies the listener's header policy before parsing; connections without a
+// header are passed through as they are
+pub fn parse_proxy_protocol_header_with_policy(
+    buffer: &[u8],
+    policy: HeaderPolicy,
+    versions: HeaderVersions,
+    crc32c_mode: ChecksumMode,
+) -> ProxyProtocolResult<'_> {
+    let has_signature = header::has_signature(buffer) == Some(true);
+
+    let header = match policy {
+        HeaderPolicy::Required => parse_proxy_protocol_header(buffer, crc32c_mode)?,
+        HeaderPolicy::Optional if has_signature => {
+            parse_proxy_protocol_header(buffer, crc32c_mode)?
+        }
+        HeaderPolicy::Forbidden if has_signature => {
+            return Err(io::Error::other(
+                "a PROXY header was sent to a listener that forbids it",
+            ))
+        }
+        _ => {
+            return Ok(ParsedHeader {
+                addresses: Addresses::Unspec,
+                rest: buffer,
+                version: 0,
+                command: Command::Proxy,
+                tlvs: Tlvs::default(),
+                raw_tlvs: &[],
+            })
+        }
+    };
+
+    if !versions.accepts(header.version) {
+        return Err(io::Error::other(format!(
+            "proxy protocol version {} is not accepted by this listener",
+            header.version
+        )));
+    }
+
+    Ok(header)
+}
+
+fn parse_proxy_protocol_header(
+    buffer: &[u8],
+    crc32c_mode: ChecksumMode,
+) -> ProxyProtocolResult<'_> {
+    let (len, header) = match header::parse(buffer) {
+        ParseResult::Complete { len, header } => (len, header),
+        ParseResult::Incomplete => return Err(io::Error::other("the PROXY header is incomplete")),
+        ParseResult::Invalid(why) => return Err(io::Error::other(why)),
+    };
+
+    let tlvs = Tlvs::parse(header.tlvs)?;
+
+    if let Some(expected) = tlvs.crc32c {
+        let actual = v2_checksum(&buffer[..len], header.tlvs);
+        if actual != expected {
+            let why = format!("CRC32C mismatch: expected {expected:#010x}, got {actual:#010x}");
+            match crc32c_mode {
+                ChecksumMode::Reject => return Err(io::Error::other(why)),
+                ChecksumMode::Log => log::warn!("{why}"),
+            }
+        }
+    }
+
+    Ok(ParsedHeader {
+        addresses: header.addresses,
+        rest: &buffer[len..],
+        version: header.version,
+        command: header.command,
+        tlvs,
+        raw_tlvs: header.tlvs,
+    })
+}
+
+// computes the checksum of the whole header with the PP2_TYPE_CRC32C value
+// zeroed out, as the spec requires; the TLVs are always at the end of the header
+fn v2_checksum(header: &[u8], tlvs: &[u8]) -> u32 {
+    let offset = match tlv::value_offset(tlvs, tlv::PP2_TYPE_CRC32C) {
+        Some(offset) => header.len() - tlvs.len() + offset,
+        None => return crc32c(&[header]),
+    };
+
+    crc32c(&[&header[..offset], &[0; 4], &header[offset + 4..]])
+}
+
+fn crc32c(chunks: &[&[u8]]) -> u32 {
+    let crc = chunks
+        .iter()
+        .flat_map(|chunk| chunk.iter())
+        .fold(!0u32, |crc, byte| {
+            CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        });
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::TlvFilter;
+
+    // TCP4 192.168.1.10:56324 -> 10.0.0.5:443 with the checksum first, then
+    // ALPN and authority, laid out as HAProxy's make_proxy_line_v2 writes them
+    const HAPROXY_V2: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x26\
+        \xc0\xa8\x01\x0a\x0a\x00\x00\x05\xdc\x04\x01\xbb\
+        \x03\x00\x04\x1c\xc7\x7c\xbb\
+        \x01\x00\x02h2\
+        \x02\x00\x0bexample.com";
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(&[b"123456789"]), 0xE3069283);
+        assert_eq!(crc32c(&[b"1234", b"", b"56789"]), 0xE3069283);
+    }
+
+    #[test]
+    fn haproxy_checksum() {
+        let parsed = parse_proxy_protocol_header(HAPROXY_V2, ChecksumMode::Reject).unwrap();
+        assert_eq!(parsed.tlvs.crc32c, Some(0x1cc77cbb));
+        assert_eq!(parsed.tlvs.authority.as_deref(), Some("example.com"));
+        assert_eq!(
+            parsed.addresses,
+            Addresses::Inet {
+                src: "192.168.1.10:56324".parse().unwrap(),
+                dst: "10.0.0.5:443".parse().unwrap(),
+            }
+        );
+        assert!(parsed.rest.is_empty());
+    }
+
+    #[test]
+    fn reemitted_tlvs_that_dont_fit() {
+        let send_proxy = SendProxy {
+            version: 2,
+            tlvs: TlvFilter::All,
+        };
+        let unix = Addresses::Unix {
+            src: &[0; header::UNIX_PATH_LEN],
+            dst: &[0; header::UNIX_PATH_LEN],
+        };
+        // as much as an IPv4 client's header can carry
+        let mut tlvs = vec![tlv::PP2_TYPE_CRC32C, 0, 4, 0, 0, 0, 0, tlv::PP2_TYPE_NOOP];
+        tlvs.extend((u16::MAX - 12 - 7 - 3).to_be_bytes());
+        tlvs.resize(u16::MAX as usize - 12, 0);
+
+        let out = encode_proxy_header(&send_proxy, Transport::Stream, &unix, &tlvs);
+        let parsed = parse_proxy_protocol_header(&out, ChecksumMode::Reject).unwrap();
+        assert_eq!(parsed.raw_tlvs.len(), 7);
+        assert!(parsed.tlvs.crc32c.is_some());
+    }
+
+    #[test]
+    fn reemitted_checksum_only_if_the_client_sent_one() {
+        let addresses = Addresses::Inet {
+            src: "192.0.2.1:5555".parse().unwrap(),
+            dst: "198.51.100.1:443".parse().unwrap(),
+        };
+        let reemit = |filter: &str, tlvs: &[u8]| {
+            let send_proxy = SendProxy {
+                version: 2,
+                tlvs: filter.parse().unwrap(),
+            };
+            let out = encode_proxy_header(&send_proxy, Transport::Stream, &addresses, tlvs);
+            parse_proxy_protocol_header(&out, ChecksumMode::Reject)
+                .unwrap()
+                .tlvs
+        };
+        let alpn = [tlv::PP2_TYPE_ALPN, 0, 2, b'h', b'2'];
+        let with_checksum = [&alpn[..], &[tlv::PP2_TYPE_CRC32C, 0, 4, 1, 2, 3, 4]].concat();
+
+        for filter in ["all", "crc32c", "alpn,crc32c"] {
+            assert_eq!(reemit(filter, &alpn).crc32c, None, "{filter}");
+            assert!(reemit(filter, &with_checksum).crc32c.is_some(), "{filter}");
+        }
+        assert_eq!(reemit("alpn", &with_checksum).crc32c, None);
+        assert_eq!(reemit("all", &alpn).alpn.as_deref(), Some(&b"h2"[..]));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut header = HAPROXY_V2.to_vec();
+        let len = header.len();
+        header[len - 1] = b'n';
+
+        assert!(parse_proxy_protocol_header(&header, ChecksumMode::Reject).is_err());
+        assert!(parse_proxy_protocol_header(&header, ChecksumMode::Log).is_ok());
     }
 }